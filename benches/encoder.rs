@@ -0,0 +1,66 @@
+//! Measures how [`Encoder`] scales with [`EncoderOptions::threads`]
+//!
+//! Run with `cargo bench --bench encoder`. Speedup is bound by the number of cores. One
+//! thread is libwebp's animation encoder, which also tries several sub-frame candidates per
+//! frame, so part of the difference to two threads is not due to the second thread
+
+use std::time::{Duration, Instant};
+
+use webp_animation::prelude::*;
+
+const FRAMES: u32 = 64;
+const SIZE: u32 = 256;
+
+fn frames() -> Vec<Vec<u8>> {
+    (0..FRAMES)
+        .map(|i| {
+            (0..SIZE * SIZE)
+                .flat_map(|p| {
+                    let (x, y) = (p % SIZE, p / SIZE);
+                    vec![(x * i) as u8, (y + i * 7) as u8, ((x ^ y) + i) as u8, 255]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn measure(frames: &[Vec<u8>], threads: usize) -> Duration {
+    let options = EncoderOptions {
+        encoding_config: Some(EncodingConfig {
+            method: 6,
+            ..EncodingConfig::new_lossy(75.)
+        }),
+        threads,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let mut encoder = Encoder::new_with_options((SIZE, SIZE), options).unwrap();
+    for (i, frame) in frames.iter().enumerate() {
+        encoder.add_frame(frame, i as i32 * 40).unwrap();
+    }
+    let webp_data = encoder.finalize(FRAMES as i32 * 40).unwrap();
+    let elapsed = start.elapsed();
+    assert!(!webp_data.is_empty());
+
+    elapsed
+}
+
+fn main() {
+    let frames = frames();
+    println!("{} frames of {}x{}, lossy, method 6", FRAMES, SIZE, SIZE);
+
+    let sequential = measure(&frames, 1);
+    for &threads in &[1, 2, 4, 8, 16, 32] {
+        let elapsed = match threads {
+            1 => sequential,
+            _ => measure(&frames, threads),
+        };
+        println!(
+            "{:>2} threads {:>10.1} ms {:>8.2}x",
+            threads,
+            elapsed.as_secs_f64() * 1e3,
+            sequential.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
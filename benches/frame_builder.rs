@@ -0,0 +1,77 @@
+//! Measures how [`FrameBuilder`] fragment encoding scales with [`FrameBuilder::set_threads`]
+//!
+//! Run with `cargo bench --bench frame_builder`. Speedup is bound by the number of cores.
+//! See the `encoder` bench for [`Encoder`] with [`EncoderOptions::threads`]
+
+use std::time::{Duration, Instant};
+
+use webp_animation::prelude::*;
+
+const FRAMES: u32 = 64;
+const SIZE: u32 = 256;
+
+fn frames() -> Vec<Vec<u8>> {
+    (0..FRAMES)
+        .map(|i| {
+            (0..SIZE * SIZE)
+                .flat_map(|p| {
+                    let (x, y) = (p % SIZE, p / SIZE);
+                    vec![(x * i) as u8, (y + i * 7) as u8, ((x ^ y) + i) as u8, 255]
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn measure(frames: &[Vec<u8>], threads: usize) -> Duration {
+    let config = EncodingConfig {
+        method: 6,
+        ..EncodingConfig::new_lossy(75.)
+    };
+    let rect = FragmentRect {
+        x_offset: 0,
+        y_offset: 0,
+        width: SIZE,
+        height: SIZE,
+    };
+
+    let start = Instant::now();
+    let mut builder = FrameBuilder::new((SIZE, SIZE)).unwrap();
+    builder.set_threads(threads).unwrap();
+    for frame in frames {
+        builder
+            .add_fragment(
+                frame,
+                rect,
+                40,
+                BlendMode::NoBlend,
+                DisposeMode::None,
+                &config,
+            )
+            .unwrap();
+    }
+    let webp_data = builder.finalize().unwrap();
+    let elapsed = start.elapsed();
+    assert!(!webp_data.is_empty());
+
+    elapsed
+}
+
+fn main() {
+    let frames = frames();
+    println!("{} frames of {}x{}, lossy, method 6", FRAMES, SIZE, SIZE);
+
+    let sequential = measure(&frames, 1);
+    for &threads in &[1, 2, 4, 8, 16, 32] {
+        let elapsed = match threads {
+            1 => sequential,
+            _ => measure(&frames, threads),
+        };
+        println!(
+            "{:>2} threads {:>10.1} ms {:>8.2}x",
+            threads,
+            elapsed.as_secs_f64() * 1e3,
+            sequential.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Compares first frame extraction with [`webp_animation::poster`] against [`Decoder`]
+//!
+//! Run with `cargo bench --bench poster`
+
+use std::time::{Duration, Instant};
+
+use webp_animation::{prelude::*, Frame};
+
+const ITERATIONS: u32 = 200;
+
+fn measure<F: FnMut() -> Frame>(name: &str, mut f: F) -> Duration {
+    // warm up
+    f();
+
+    // use the output, so that decoding is not optimized away
+    let mut bytes = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        bytes += f().data().len();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    assert!(bytes > 0);
+
+    println!("{:<30} {:>10.1} us/iter", name, elapsed.as_secs_f64() * 1e6);
+    elapsed
+}
+
+fn main() {
+    for file in ["./data/animated.webp", "./data/example.webp"] {
+        let buffer = std::fs::read(file).unwrap();
+        println!("{}", file);
+
+        let decoder = measure("Decoder first frame", || {
+            Decoder::new(&buffer).unwrap().into_iter().next().unwrap()
+        });
+        let poster = measure("poster", || {
+            webp_animation::poster(&buffer, ColorMode::Rgba).unwrap()
+        });
+
+        println!(
+            "{:<30} {:>10.2}x\n",
+            "speedup",
+            decoder.as_secs_f64() / poster.as_secs_f64()
+        );
+    }
+}
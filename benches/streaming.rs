@@ -0,0 +1,50 @@
+//! Measures [`StreamingDecoder`] with data pushed in pieces of different sizes
+//!
+//! Run with `cargo bench --bench streaming`
+
+use std::time::{Duration, Instant};
+
+use webp_animation::prelude::*;
+
+const ITERATIONS: u32 = 20;
+
+fn measure(buffer: &[u8], chunk_size: usize) -> Duration {
+    let decode = || {
+        let mut decoder = StreamingDecoder::new();
+        let mut frames = 0;
+        for chunk in buffer.chunks(chunk_size) {
+            decoder.push(chunk);
+            while let StreamingStatus::Frame(_) = decoder.next_frame().unwrap() {
+                frames += 1;
+            }
+        }
+        frames
+    };
+
+    // warm up
+    let frames = decode();
+    assert!(frames > 0);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(decode(), frames);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    for file in ["./data/animated.webp", "./data/example.webp"] {
+        let buffer = std::fs::read(file).unwrap();
+        println!("{} ({} bytes)", file, buffer.len());
+
+        for chunk_size in [1, 16, 256, 4096, buffer.len()] {
+            let elapsed = measure(&buffer, chunk_size);
+            println!(
+                "{:<30} {:>10.1} us/iter",
+                format!("push {} bytes at a time", chunk_size),
+                elapsed.as_secs_f64() * 1e6
+            );
+        }
+        println!();
+    }
+}
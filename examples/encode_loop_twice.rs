@@ -3,7 +3,7 @@ use log::info;
 use std::fs;
 
 use imageproc::{drawing, rect::Rect};
-use webp_animation::{AnimParams, Encoder, EncoderOptions};
+use webp_animation::{AnimParams, Encoder, EncoderOptions, LoopCount};
 
 fn main() {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
@@ -15,7 +15,7 @@ fn main() {
         (width, height),
         EncoderOptions {
             anim_params: AnimParams {
-                loop_count: 2,
+                loop_count: LoopCount::new(2).unwrap(),
                 ..Default::default()
             },
             ..Default::default()
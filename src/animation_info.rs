@@ -0,0 +1,80 @@
+use std::mem;
+
+use libwebp_sys as webp;
+
+use crate::{demuxer::Demuxer, DecodingError, Error, LoopCount};
+
+/// Global information about a webp animation, as stored in its headers
+///
+/// Available without decoding any frames with [`Decoder::info`](crate::Decoder::info)
+///
+/// ```rust
+/// use webp_animation::prelude::*;
+///
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let decoder = Decoder::new(&buffer).unwrap();
+/// let info = decoder.info();
+///
+/// assert_eq!(info.dimensions, (400, 400));
+/// assert_eq!(info.frame_count, 10);
+/// assert_eq!(info.duration_ms, 400);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AnimationInfo {
+    /// Canvas dimensions (`width`, `height`)
+    pub dimensions: (u32, u32),
+    /// Number of times the animation should be played
+    pub loop_count: LoopCount,
+    /// Canvas background color in Rgba order. This is a hint only, frames are
+    /// always decoded on a transparent canvas
+    pub background_color: [u8; 4],
+    /// Number of frames
+    pub frame_count: u32,
+    /// Total duration of all frames, in milliseconds
+    pub duration_ms: u64,
+    /// Whether any of the frames contains alpha
+    pub has_alpha: bool,
+    /// Whether the file is an animation (has `ANIM` and `ANMF` chunks). Still
+    /// images are decoded as a single frame
+    pub is_animated: bool,
+    /// Whether the file contains an ICC color profile
+    pub has_icc_profile: bool,
+    /// Whether the file contains EXIF metadata
+    pub has_exif: bool,
+    /// Whether the file contains XMP metadata
+    pub has_xmp: bool,
+    /// Size of the webp data in bytes
+    pub file_size: usize,
+}
+
+impl AnimationInfo {
+    pub(crate) fn new(buffer: &[u8], anim_info: &webp::WebPAnimInfo) -> Result<Self, Error> {
+        let features = unsafe {
+            let mut features = mem::zeroed();
+            let status = webp::WebPGetFeatures(buffer.as_ptr(), buffer.len(), &mut features);
+            DecodingError::check(status).map_err(Error::DecodeFailed)?;
+            features
+        };
+
+        let demuxer = Demuxer::new(buffer)?;
+        let flags = demuxer.format_flags();
+
+        // libwebp stores the color as 0xAARRGGBB
+        let [a, r, g, b] = anim_info.bgcolor.to_be_bytes();
+
+        Ok(Self {
+            dimensions: (anim_info.canvas_width, anim_info.canvas_height),
+            // stored as 16 bits in the ANIM chunk, always in range
+            loop_count: LoopCount::new(anim_info.loop_count)?,
+            background_color: [r, g, b, a],
+            frame_count: anim_info.frame_count,
+            duration_ms: demuxer.duration(),
+            has_alpha: features.has_alpha != 0,
+            is_animated: features.has_animation != 0,
+            has_icc_profile: flags & webp::ICCP_FLAG != 0,
+            has_exif: flags & webp::EXIF_FLAG != 0,
+            has_xmp: flags & webp::XMP_FLAG != 0,
+            file_size: buffer.len(),
+        })
+    }
+}
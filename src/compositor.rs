@@ -0,0 +1,446 @@
+use std::{mem, time::Instant};
+
+use libwebp_sys as webp;
+
+use crate::{
+    demuxer::{is_keyframe, Demuxer, FrameHeader, FrameIndex, RawFragment},
+    ColorMode, CropRect, DecoderLimits, DecoderOptions, DecodingError, Error, Frame,
+};
+
+const NUM_CHANNELS: usize = 4;
+
+/// Reconstructs full animation frames from demuxed [`RawFragment`]'s
+///
+/// This is a port of the canvas handling of libwebp `WebPAnimDecoder` (`anim_decode.c`).
+/// Unlike the libwebp one, it can be fed with frames one at a time and can start from
+/// any keyframe. Output is byte-identical to `WebPAnimDecoder`
+///
+/// If cropping or scaling is requested in [`DecoderOptions`], fragments are decoded directly
+/// at the output scale, and composited on a canvas of the output size
+pub(crate) struct Compositor {
+    /// Dimensions of the animation canvas
+    canvas_dimensions: (u32, u32),
+    crop: CropRect,
+    /// Dimensions of the output canvas
+    dimensions: (u32, u32),
+    color_mode: ColorMode,
+    use_threads: bool,
+    limits: DecoderLimits,
+
+    /// Current output canvas in Rgba (premultiplied for premultiplied color modes), not yet
+    /// disposed
+    canvas: Vec<u8>,
+    /// Previous canvas with dispose method applied, valid while compositing a non-keyframe
+    disposed: Vec<u8>,
+
+    previous: Option<FrameHeader>,
+    previous_was_keyframe: bool,
+    timestamp: i32,
+    next_index: u32,
+}
+
+impl Compositor {
+    /// Construct a compositor for an animation with `canvas_dimensions`. Returns an error if
+    /// the crop rectangle or output size of `options` is invalid for the canvas
+    pub fn new(canvas_dimensions: (u32, u32), options: &DecoderOptions) -> Result<Self, Error> {
+        let (crop, dimensions) = options.output_geometry(canvas_dimensions)?;
+        let size = dimensions.0 as usize * dimensions.1 as usize * NUM_CHANNELS;
+
+        Ok(Self {
+            canvas_dimensions,
+            crop,
+            dimensions,
+            color_mode: options.color_mode,
+            use_threads: options.use_threads,
+            limits: options.limits,
+            canvas: vec![0; size],
+            disposed: vec![0; size],
+            previous: None,
+            previous_was_keyframe: false,
+            timestamp: 0,
+            next_index: 0,
+        })
+    }
+
+    /// Dimensions of the output frames
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// The current output canvas in Rgba (rgbA for premultiplied color modes)
+    pub fn canvas(&self) -> &[u8] {
+        &self.canvas
+    }
+
+    /// Presentation timestamp of the current canvas
+    pub fn timestamp(&self) -> i32 {
+        self.timestamp
+    }
+
+    /// Index of the frame expected next by [`Compositor::composite`]
+    pub fn next_index(&self) -> u32 {
+        self.next_index
+    }
+
+    /// Rewind to just before the keyframe at `index`. `timestamp` is the presentation
+    /// timestamp of the frame preceding it
+    fn reset_to(&mut self, index: u32, timestamp: i32) {
+        self.previous = None;
+        self.previous_was_keyframe = false;
+        self.timestamp = timestamp;
+        self.next_index = index;
+    }
+
+    /// Bring the canvas to frame `index`, compositing forward from the closest keyframe
+    /// (or from the current frame, if it is on the way)
+    pub fn seek(
+        &mut self,
+        demuxer: &Demuxer,
+        frames: &FrameIndex,
+        index: usize,
+    ) -> Result<(), Error> {
+        let keyframe = frames.keyframe_before(index);
+        let next_index = self.next_index as usize;
+
+        if next_index < keyframe || next_index > index + 1 {
+            self.reset_to(keyframe as u32, frames.timestamp_before(keyframe));
+        }
+
+        while self.next_index as usize <= index {
+            let result = demuxer
+                .fragment(self.next_index)
+                .ok_or(Error::DecodeFailed(DecodingError::BitstreamError))
+                .and_then(|fragment| self.composite(&fragment));
+
+            if let Err(e) = result {
+                // canvas is in an unknown state
+                self.reset_to(0, 0);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `header` can be composited without knowing the previous canvas state
+    pub fn is_keyframe(&self, header: &FrameHeader) -> bool {
+        is_keyframe(
+            header,
+            self.previous.as_ref(),
+            self.previous_was_keyframe,
+            self.canvas_dimensions,
+        )
+    }
+
+    /// Decode `fragment` and draw it onto the canvas on top of the previous frame
+    pub fn composite(&mut self, fragment: &RawFragment) -> Result<(), Error> {
+        let header = &fragment.header;
+        let (width, height) = self.canvas_dimensions;
+
+        if header.x_offset + header.width > width || header.y_offset + header.height > height {
+            return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+        }
+
+        let start = Instant::now();
+        // placement of the fragment on the output canvas
+        let rect = self.output_rect(header);
+
+        let keyframe = self.is_keyframe(header);
+        if keyframe {
+            // a fragment covering the whole canvas overwrites every pixel
+            if (rect.width, rect.height) != self.dimensions {
+                self.canvas.iter_mut().for_each(|b| *b = 0);
+            }
+        } else {
+            self.dispose_previous();
+        }
+
+        if rect.width > 0 && rect.height > 0 {
+            self.decode(fragment, &rect)?;
+        }
+
+        // decoding may have set some pixels (semi-)transparent, these have to be blended
+        // against the previous canvas
+        if header.blend && !keyframe {
+            if let Some(previous) = self.previous {
+                let previous = self.output_rect(&previous);
+
+                for y in rect.y_offset..rect.y_offset + rect.height {
+                    if previous.dispose_background {
+                        // pixels within disposed area would be blended against transparent = no-op
+                        for (left, width) in blend_ranges(&rect, &previous, y).iter().flatten() {
+                            self.blend_row(y, *left, *width);
+                        }
+                    } else {
+                        self.blend_row(y, rect.x_offset, rect.width);
+                    }
+                }
+            }
+        }
+
+        self.limits.check_decode_time(start.elapsed())?;
+
+        // 24-bit durations of enough frames overflow i32, stick to the last representable time
+        self.timestamp = self.timestamp.saturating_add(header.duration);
+        self.previous = Some(*header);
+        self.previous_was_keyframe = keyframe;
+        self.next_index = header.index + 1;
+
+        Ok(())
+    }
+
+    /// Apply the dispose method of the previous frame to the canvas, and keep a copy of the
+    /// result for blending. Done only when the next frame is composited on top of it, so that
+    /// keyframes (e.g. a single poster frame) skip the copy
+    fn dispose_previous(&mut self) {
+        if let Some(previous) = self.previous {
+            if previous.dispose_background {
+                let rect = self.output_rect(&previous);
+                let stride = self.dimensions.0 as usize * NUM_CHANNELS;
+                for y in rect.y_offset..rect.y_offset + rect.height {
+                    let start = y as usize * stride + rect.x_offset as usize * NUM_CHANNELS;
+                    let end = start + rect.width as usize * NUM_CHANNELS;
+                    self.canvas[start..end].iter_mut().for_each(|b| *b = 0);
+                }
+            }
+        }
+
+        self.disposed.copy_from_slice(&self.canvas);
+    }
+
+    /// Output the current canvas as a [`Frame`], without copying it if the color mode
+    /// matches the canvas
+    pub fn into_frame(self) -> Frame {
+        let data = match self.color_mode {
+            ColorMode::Rgba | ColorMode::RgbaPremultiplied => self.canvas,
+            _ => {
+                let mut data = Vec::new();
+                convert(&self.canvas, self.color_mode, &mut data);
+                data
+            }
+        };
+
+        Frame::new_from_decoder(self.timestamp, self.color_mode, data, self.dimensions)
+    }
+
+    /// Output the current canvas as a [`Frame`]
+    pub fn frame(&self) -> Frame {
+        let mut data = Vec::new();
+        convert(&self.canvas, self.color_mode, &mut data);
+
+        Frame::new_from_decoder(self.timestamp, self.color_mode, data, self.dimensions)
+    }
+
+    /// Map the area of `header` on the animation canvas onto the output canvas. Parts
+    /// outside of the crop rectangle are left out, so the result may be empty
+    fn output_rect(&self, header: &FrameHeader) -> FrameHeader {
+        let crop = &self.crop;
+        let scale = |value: u32, start: u32, size: u32, output: u32| {
+            let value = value.max(start).min(start + size) - start;
+            (value as u64 * output as u64 / size as u64) as u32
+        };
+        let scale_x = |x| scale(x, crop.left, crop.width, self.dimensions.0);
+        let scale_y = |y| scale(y, crop.top, crop.height, self.dimensions.1);
+
+        let left = scale_x(header.x_offset);
+        let top = scale_y(header.y_offset);
+
+        FrameHeader {
+            x_offset: left,
+            y_offset: top,
+            width: scale_x(header.x_offset + header.width) - left,
+            height: scale_y(header.y_offset + header.height) - top,
+            ..*header
+        }
+    }
+
+    /// Decode `fragment` onto `rect` of the output canvas, cropping and scaling as needed
+    fn decode(&mut self, fragment: &RawFragment, rect: &FrameHeader) -> Result<(), Error> {
+        let header = &fragment.header;
+        let stride = self.dimensions.0 as usize * NUM_CHANNELS;
+        let offset = rect.y_offset as usize * stride + rect.x_offset as usize * NUM_CHANNELS;
+
+        let mut config: webp::WebPDecoderConfig = unsafe { mem::zeroed() };
+        if unsafe { webp::WebPInitDecoderConfig(&mut config) } == 0 {
+            return Err(Error::OptionsInitFailed);
+        }
+
+        let output = &mut self.canvas[offset..];
+
+        config.output.colorspace = match self.color_mode.is_premultiplied() {
+            true => webp::MODE_rgbA,
+            false => webp::MODE_RGBA,
+        };
+        config.output.is_external_memory = 1;
+        config.output.u.RGBA = webp::WebPRGBABuffer {
+            rgba: output.as_mut_ptr(),
+            stride: stride as i32,
+            size: output.len(),
+        };
+        config.options.use_threads = if self.use_threads { 1 } else { 0 };
+
+        // part of the fragment within the crop rectangle
+        let crop = &self.crop;
+        let left = header.x_offset.max(crop.left);
+        let top = header.y_offset.max(crop.top);
+        let right = (header.x_offset + header.width).min(crop.left + crop.width);
+        let bottom = (header.y_offset + header.height).min(crop.top + crop.height);
+
+        if (left, top, right - left, bottom - top)
+            != (
+                header.x_offset,
+                header.y_offset,
+                header.width,
+                header.height,
+            )
+        {
+            config.options.use_cropping = 1;
+            config.options.crop_left = (left - header.x_offset) as i32;
+            config.options.crop_top = (top - header.y_offset) as i32;
+            config.options.crop_width = (right - left) as i32;
+            config.options.crop_height = (bottom - top) as i32;
+        }
+
+        if (rect.width, rect.height) != (right - left, bottom - top) {
+            config.options.use_scaling = 1;
+            config.options.scaled_width = rect.width as i32;
+            config.options.scaled_height = rect.height as i32;
+        }
+
+        let status =
+            unsafe { webp::WebPDecode(fragment.data.as_ptr(), fragment.data.len(), &mut config) };
+
+        DecodingError::check(status).map_err(Error::DecodeFailed)
+    }
+
+    fn blend_row(&mut self, y: u32, left: u32, width: u32) {
+        let start = (y as usize * self.dimensions.0 as usize + left as usize) * NUM_CHANNELS;
+        let end = start + width as usize * NUM_CHANNELS;
+
+        let src = self.canvas[start..end].chunks_exact_mut(NUM_CHANNELS);
+        let dst = self.disposed[start..end].chunks_exact(NUM_CHANNELS);
+        let premultiplied = self.color_mode.is_premultiplied();
+
+        for (src, dst) in src.zip(dst) {
+            if src[3] != 0xff {
+                match premultiplied {
+                    true => blend_pixel_premult(src, dst),
+                    false => blend_pixel_non_premult(src, dst),
+                }
+            }
+        }
+    }
+}
+
+/// Horizontal range (`left`, `width`) on a canvas row
+type Span = (u32, u32);
+
+/// Ranges at canvas row `y` that belong to `src` but not `dst`
+fn blend_ranges(src: &FrameHeader, dst: &FrameHeader, y: u32) -> [Option<Span>; 2] {
+    let src_max_x = src.x_offset + src.width;
+    let dst_max_x = dst.x_offset + dst.width;
+    let dst_max_y = dst.y_offset + dst.height;
+
+    if y < dst.y_offset || y >= dst_max_y || src.x_offset >= dst_max_x || src_max_x <= dst.x_offset
+    {
+        return [Some((src.x_offset, src.width)), None];
+    }
+
+    let left = if src.x_offset < dst.x_offset {
+        Some((src.x_offset, dst.x_offset - src.x_offset))
+    } else {
+        None
+    };
+
+    let right = if src_max_x > dst_max_x {
+        Some((dst_max_x, src_max_x - dst_max_x))
+    } else {
+        None
+    };
+
+    [left, right]
+}
+
+/// Blend `src` over `dst`, non-premultiplied. Same integer arithmetic as libwebp
+fn blend_pixel_non_premult(src: &mut [u8], dst: &[u8]) {
+    let src_a = src[3] as u32;
+    if src_a == 0 {
+        src.copy_from_slice(dst);
+        return;
+    }
+
+    // approximates dst_a * (255 - src_a) / 255
+    let dst_factor_a = (dst[3] as u32 * (256 - src_a)) >> 8;
+    let blend_a = src_a + dst_factor_a;
+    let scale = (1u32 << 24) / blend_a;
+
+    for c in 0..3 {
+        let blend_unscaled = src[c] as u32 * src_a + dst[c] as u32 * dst_factor_a;
+        src[c] = ((blend_unscaled * scale) >> 24) as u8;
+    }
+    src[3] = blend_a as u8;
+}
+
+/// Blend `src` over `dst`, both premultiplied. Same integer arithmetic as libwebp
+fn blend_pixel_premult(src: &mut [u8], dst: &[u8]) {
+    let src_a = src[3] as u32;
+    let dst = u32::from_le_bytes([dst[0], dst[1], dst[2], dst[3]]);
+
+    // multiplies each channel of dst by (256 - src_a) / 256
+    let mask = 0x00ff00ff;
+    let scale = 256 - src_a;
+    let rb = ((dst & mask) * scale) >> 8;
+    let ag = ((dst >> 8) & mask) * scale;
+    let dst = (rb & mask) | (ag & !mask);
+
+    let blended = u32::from_le_bytes([src[0], src[1], src[2], src[3]]).wrapping_add(dst);
+    src.copy_from_slice(&blended.to_le_bytes());
+}
+
+/// Convert Rgba canvas data (rgbA for premultiplied modes) into `color_mode`, replacing
+/// the contents of `output`
+pub(crate) fn convert(rgba: &[u8], color_mode: ColorMode, output: &mut Vec<u8>) {
+    let pixels = rgba.chunks_exact(NUM_CHANNELS);
+    output.clear();
+
+    match color_mode {
+        ColorMode::Rgba | ColorMode::RgbaPremultiplied => output.extend_from_slice(rgba),
+        ColorMode::Bgra | ColorMode::BgraPremultiplied => {
+            output.extend(pixels.flat_map(|p| [p[2], p[1], p[0], p[3]]))
+        }
+        ColorMode::ArgbPremultiplied => {
+            output.extend(pixels.flat_map(|p| [p[3], p[0], p[1], p[2]]))
+        }
+        ColorMode::Rgb => output.extend(pixels.flat_map(|p| [p[0], p[1], p[2]])),
+        ColorMode::Bgr => output.extend(pixels.flat_map(|p| [p[2], p[1], p[0]])),
+        ColorMode::Rgb565 => output.extend(pixels.flat_map(|p| {
+            [
+                (p[0] & 0xf8) | (p[1] >> 5),
+                ((p[1] << 3) & 0xe0) | (p[2] >> 3),
+            ]
+        })),
+        ColorMode::Rgba4444 => output.extend(
+            pixels.flat_map(|p| [(p[0] & 0xf0) | (p[1] >> 4), (p[2] & 0xf0) | (p[3] >> 4)]),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blend_pixel_premult() {
+        let mut src = [100, 50, 0, 128];
+        blend_pixel_premult(&mut src, &[200, 100, 40, 255]);
+        assert_eq!(src, [200, 100, 20, 255]);
+
+        let mut src = [0, 0, 0, 0];
+        blend_pixel_premult(&mut src, &[20, 10, 5, 30]);
+        assert_eq!(src, [20, 10, 5, 30]);
+
+        let mut src = [10, 20, 30, 255];
+        blend_pixel_premult(&mut src, &[200, 100, 40, 255]);
+        assert_eq!(src, [10, 20, 30, 255]);
+    }
+}
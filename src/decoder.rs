@@ -1,10 +1,19 @@
-use std::{fmt::Debug, mem, pin::Pin};
+use std::{
+    fmt::Debug,
+    mem,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
 use libwebp_sys as webp;
 
-use crate::{ColorMode, Error, Frame};
+use crate::{
+    compositor::{convert, Compositor},
+    demuxer::{Demuxer, FrameIndex},
+    AnimationInfo, ColorMode, DecodingError, Error, Frame, FrameRef,
+};
 
-const MAX_CANVAS_SIZE: usize = 3840 * 2160; // 4k
+pub(crate) const MAX_CANVAS_SIZE: usize = 3840 * 2160; // 4k
 
 /// An options struct for [`Decoder`]
 ///
@@ -14,6 +23,15 @@ pub struct DecoderOptions {
     pub use_threads: bool,
     /// Output colorspace. [`ColorMode::Rgba`] by default. Affects [`Frame`] output
     pub color_mode: ColorMode,
+    /// Resource limits for the input. See [`DecoderLimits`] for defaults
+    pub limits: DecoderLimits,
+    /// Decode only this area of the animation canvas. Whole canvas by default
+    pub crop: Option<CropRect>,
+    /// Scale the (cropped) canvas to this size (`width`, `height`). Unscaled by default
+    ///
+    /// Frames are decoded directly at the output size, so memory use and decoding time
+    /// follow the output size instead of the animation canvas size
+    pub output_size: Option<(u32, u32)>,
 }
 
 impl Default for DecoderOptions {
@@ -21,6 +39,173 @@ impl Default for DecoderOptions {
         Self {
             use_threads: true,
             color_mode: ColorMode::Rgba,
+            limits: Default::default(),
+            crop: None,
+            output_size: None,
+        }
+    }
+}
+
+impl DecoderOptions {
+    /// Whether frames are cropped or scaled
+    pub(crate) fn is_scaled(&self) -> bool {
+        self.crop.is_some() || self.output_size.is_some()
+    }
+
+    /// Crop rectangle and output dimensions for an animation with `canvas` dimensions.
+    /// Returns an error if either is empty, or if the crop rectangle exceeds the canvas
+    pub(crate) fn output_geometry(
+        &self,
+        (width, height): (u32, u32),
+    ) -> Result<(CropRect, (u32, u32)), Error> {
+        let crop = self.crop.unwrap_or(CropRect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        });
+
+        if crop.width == 0
+            || crop.height == 0
+            || crop.left as u64 + crop.width as u64 > width as u64
+            || crop.top as u64 + crop.height as u64 > height as u64
+        {
+            return Err(Error::InvalidCrop(crop));
+        }
+
+        let output_size = self.output_size.unwrap_or((crop.width, crop.height));
+        if output_size.0 == 0 || output_size.1 == 0 {
+            return Err(Error::InvalidOutputSize(output_size.0, output_size.1));
+        }
+
+        Ok((crop, output_size))
+    }
+}
+
+/// A rectangle on the animation canvas, in pixels. See [`DecoderOptions::crop`]
+///
+/// ```
+/// # use webp_animation::prelude::*;
+/// #
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let decoder = Decoder::new_with_options(&buffer, DecoderOptions {
+///   crop: Some(CropRect { left: 100, top: 100, width: 200, height: 200 }),
+///   output_size: Some((64, 64)),
+///   ..Default::default()
+/// }).unwrap();
+///
+/// let frame = decoder.into_iter().next().unwrap();
+/// assert_eq!(frame.dimensions(), (64, 64));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CropRect {
+    /// Distance from the left edge of the canvas
+    pub left: u32,
+    /// Distance from the top edge of the canvas
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Resource limits for decoding untrusted webp data
+///
+/// Exceeding a limit results in an [`Error`] naming the limit. By default, only the
+/// canvas size is limited
+///
+/// ```
+/// # use webp_animation::prelude::*;
+/// #
+/// let options = DecoderOptions {
+///   limits: DecoderLimits {
+///     max_canvas_pixels: 7680 * 4320, // 8k
+///     max_frame_count: Some(1000),
+///     ..Default::default()
+///   },
+///   ..Default::default()
+/// };
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DecoderLimits {
+    /// Maximum canvas size (`width * height`) in pixels. 3840 * 2160 by default
+    pub max_canvas_pixels: usize,
+    /// Maximum number of frames in the animation. Unlimited by default
+    pub max_frame_count: Option<u32>,
+    /// Maximum total duration of the animation in milliseconds. Unlimited by default
+    pub max_duration_ms: Option<u64>,
+    /// Maximum total size of all decoded frames in bytes (`frame count * width * height *
+    /// color mode size`). Unlimited by default
+    pub max_decoded_bytes: Option<usize>,
+    /// Maximum time spent decoding a single frame. Unlimited by default
+    ///
+    /// This is a post-hoc check, libwebp can not be interrupted while decoding: the frame
+    /// that exceeds the limit is fully decoded first, then discarded and
+    /// [`Error::FrameDecodeTimeExceeded`] returned. It stops decoding of the following
+    /// frames, but does not bound the time taken by a single frame
+    pub max_frame_decode_time: Option<Duration>,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        Self {
+            max_canvas_pixels: MAX_CANVAS_SIZE,
+            max_frame_count: None,
+            max_duration_ms: None,
+            max_decoded_bytes: None,
+            max_frame_decode_time: None,
+        }
+    }
+}
+
+impl DecoderLimits {
+    pub(crate) fn check_canvas(&self, (width, height): (u32, u32)) -> Result<(), Error> {
+        if width as usize * height as usize > self.max_canvas_pixels {
+            return Err(Error::TooLargeCanvas(width, height, self.max_canvas_pixels));
+        }
+
+        Ok(())
+    }
+
+    /// Check frame count and the total decoded size of `frame_count` frames
+    pub(crate) fn check_frame_count(
+        &self,
+        frame_count: u32,
+        (width, height): (u32, u32),
+        color_mode: ColorMode,
+    ) -> Result<(), Error> {
+        if let Some(max_frame_count) = self.max_frame_count {
+            if frame_count > max_frame_count {
+                return Err(Error::TooManyFrames(frame_count, max_frame_count));
+            }
+        }
+
+        if let Some(max_decoded_bytes) = self.max_decoded_bytes {
+            let decoded_bytes = (frame_count as usize)
+                .saturating_mul(width as usize * height as usize)
+                .saturating_mul(color_mode.size());
+
+            if decoded_bytes > max_decoded_bytes {
+                return Err(Error::TooManyDecodedBytes(decoded_bytes, max_decoded_bytes));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn check_duration(&self, duration_ms: u64) -> Result<(), Error> {
+        match self.max_duration_ms {
+            Some(max_duration_ms) if duration_ms > max_duration_ms => {
+                Err(Error::TooLongDuration(duration_ms, max_duration_ms))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_decode_time(&self, elapsed: Duration) -> Result<(), Error> {
+        match self.max_frame_decode_time {
+            Some(max_frame_decode_time) if elapsed > max_frame_decode_time => Err(
+                Error::FrameDecodeTimeExceeded(elapsed, max_frame_decode_time),
+            ),
+            _ => Ok(()),
         }
     }
 }
@@ -56,13 +241,27 @@ impl Default for DecoderOptions {
 ///   assert_eq!(frame.into_image().unwrap().dimensions(), (400, 400));
 /// }
 /// ```
+///
+/// A decoder that owns its buffer can be created with [`Decoder::new_owned`]
 pub struct Decoder<'a> {
-    buffer: &'a [u8],
-    decoder_wr: DecoderWrapper,
+    /// `None` for cropped or scaled decoding, where frames are composited by `seeker`
+    decoder_wr: Option<DecoderWrapper>,
     info: webp::WebPAnimInfo,
+    animation_info: AnimationInfo,
     options: DecoderOptions,
+    /// Dimensions of the output frames
+    dimensions: (u32, u32),
+    seeker: Option<Seeker>,
+    position: Position,
+    /// Output canvas for color modes that libwebp can not produce directly
+    converted: Vec<u8>,
+    /// Declared last, so that it is dropped after `decoder_wr` referencing it
+    buffer: DecoderBuffer<'a>,
 }
 
+/// A [`Decoder`] that owns its input buffer. Can be stored freely and sent to other threads
+pub type OwnedDecoder = Decoder<'static>;
+
 impl<'a> Decoder<'a> {
     /// Construct a new decoder from webp `buffer`
     ///
@@ -88,184 +287,371 @@ impl<'a> Decoder<'a> {
     /// let buf = std::fs::read("./data/animated.webp").unwrap();
     /// let decoder = Decoder::new_with_options(&buf, DecoderOptions {
     ///   use_threads: false,
-    ///   color_mode: ColorMode::Bgra
+    ///   color_mode: ColorMode::Bgra,
+    ///   ..Default::default()
     /// }).unwrap();
     /// ```
     pub fn new_with_options(buffer: &'a [u8], options: DecoderOptions) -> Result<Self, Error> {
-        if buffer.is_empty() {
+        Decoder::from_buffer(DecoderBuffer::Borrowed(buffer), options)
+    }
+
+    fn from_buffer(buffer: DecoderBuffer<'a>, options: DecoderOptions) -> Result<Self, Error> {
+        let bytes = buffer.as_slice();
+        if bytes.is_empty() {
             return Err(Error::ZeroSizeBuffer);
         }
 
-        let mut decoder_options = Box::pin(unsafe {
-            let mut options = mem::zeroed();
-
-            if webp::WebPAnimDecoderOptionsInit(&mut options) != 1 {
-                return Err(Error::OptionsInitFailed);
+        let (decoder_wr, info) = match options.is_scaled() {
+            // WebPAnimDecoder can not scale, and would allocate the full size canvas
+            true => (None, Demuxer::new(bytes)?.anim_info()),
+            false => {
+                let decoder_wr = DecoderWrapper::new(bytes, &options)?;
+                let info = decoder_wr.info()?;
+                (Some(decoder_wr), info)
             }
-
-            options
-        });
-
-        decoder_options.use_threads = if options.use_threads { 1 } else { 0 };
-        decoder_options.color_mode = match options.color_mode {
-            ColorMode::Rgba => libwebp_sys::MODE_RGBA,
-            ColorMode::Bgra => libwebp_sys::MODE_BGRA,
-            ColorMode::Rgb => libwebp_sys::MODE_RGB,
-            ColorMode::Bgr => libwebp_sys::MODE_BGR,
         };
 
-        // pin data (& options above) because decoder takes reference to them
-        let data = Box::pin(webp::WebPData {
-            bytes: buffer.as_ptr(),
-            size: buffer.len(),
-        });
-
-        let decoder_wr = DecoderWrapper::new(data, decoder_options)?;
-
-        let info = unsafe {
-            let mut info = mem::zeroed();
-            if webp::WebPAnimDecoderGetInfo(decoder_wr.decoder, &mut info) != 1 {
-                return Err(Error::DecoderGetInfoFailed);
-            }
-            info
-        };
+        let animation_info = AnimationInfo::new(bytes, &info)?;
 
         // prevent too large allocations
-        if info.canvas_width * info.canvas_height > MAX_CANVAS_SIZE as u32 {
-            return Err(Error::TooLargeCanvas(
-                info.canvas_width,
-                info.canvas_height,
-                MAX_CANVAS_SIZE,
-            ));
-        }
+        let limits = &options.limits;
+        limits.check_canvas(animation_info.dimensions)?;
+        let (_, dimensions) = options.output_geometry(animation_info.dimensions)?;
+        limits.check_canvas(dimensions)?;
+        limits.check_frame_count(info.frame_count, dimensions, options.color_mode)?;
+        limits.check_duration(animation_info.duration_ms)?;
 
         log::trace!("Decoder initialized. {:?}", info);
 
         Ok(Self {
-            buffer,
             decoder_wr,
             info,
+            animation_info,
             options,
+            dimensions,
+            seeker: None,
+            position: Position::default(),
+            converted: Vec::new(),
+            buffer,
         })
     }
 
     /// Returns dimensions for webp frames (`width`, `height`)
     ///
+    /// These are the canvas dimensions, unless [`DecoderOptions::crop`] or
+    /// [`DecoderOptions::output_size`] is set. See [`AnimationInfo::dimensions`] for the
+    /// canvas dimensions
+    ///
     /// ```
     /// # use webp_animation::prelude::*;
     /// #
     /// let buffer = std::fs::read("./data/animated.webp").unwrap();
     /// let decoder = Decoder::new(&buffer).unwrap();
     /// assert_eq!(decoder.dimensions(), (400, 400));
+    ///
+    /// let decoder = Decoder::new_with_options(&buffer, DecoderOptions {
+    ///   output_size: Some((128, 128)),
+    ///   ..Default::default()
+    /// }).unwrap();
+    /// assert_eq!(decoder.dimensions(), (128, 128));
     /// ```
     pub fn dimensions(&self) -> (u32, u32) {
-        (self.info.canvas_width, self.info.canvas_height)
+        self.dimensions
     }
 
-    fn has_more_frames(&self) -> bool {
-        let frames = unsafe { webp::WebPAnimDecoderHasMoreFrames(self.decoder_wr.decoder) };
-        frames > 0
+    /// Returns global information about the animation, see [`AnimationInfo`]
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let decoder = Decoder::new(&buffer).unwrap();
+    /// assert_eq!(decoder.info().loop_count, LoopCount::Infinite);
+    /// ```
+    pub fn info(&self) -> AnimationInfo {
+        self.animation_info
     }
-}
 
-impl<'a> Debug for Decoder<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let info = &self.info;
+    /// Returns the ICC color profile (`ICCP` chunk) of the file, if any
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/metadata.webp").unwrap();
+    /// let decoder = Decoder::new(&buffer).unwrap();
+    /// assert_eq!(decoder.icc_profile().unwrap().len(), 48);
+    /// ```
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.chunk(b"ICCP")
+    }
 
-        write!(f, "Decoder {{ buffer: {}b, info: {{ w: {}, h: {}, loop_cnt: {}, bgcolor: 0x{:x}, frame_count: {} }} }}", self.buffer.len(), info.canvas_width, info.canvas_height, info.loop_count, info.bgcolor, info.frame_count)
+    /// Returns the EXIF metadata (`EXIF` chunk) of the file, if any
+    pub fn exif(&self) -> Option<&[u8]> {
+        self.chunk(b"EXIF")
     }
-}
 
-struct DecoderWrapper {
-    decoder: *mut webp::WebPAnimDecoder,
+    /// Returns the XMP metadata (`XMP ` chunk) of the file, if any
+    pub fn xmp(&self) -> Option<&[u8]> {
+        self.chunk(b"XMP ")
+    }
 
-    #[allow(dead_code)]
-    data: Pin<Box<webp::WebPData>>,
-    #[allow(dead_code)]
-    options: Pin<Box<webp::WebPAnimDecoderOptions>>,
-}
+    /// Returns the payload of the first chunk with id `fourcc`, if any
+    ///
+    /// The returned slice borrows the input buffer, no data is copied. Only metadata and
+    /// unknown chunks are available; `VP8X`, `ANIM`, `ANMF` and image data chunks are not
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/metadata.webp").unwrap();
+    /// let decoder = Decoder::new(&buffer).unwrap();
+    /// assert!(decoder.chunk(b"XMP ").unwrap().starts_with(b"<x:xmpmeta"));
+    /// assert_eq!(decoder.chunk(b"ANIM"), None);
+    /// ```
+    pub fn chunk(&self, fourcc: &[u8; 4]) -> Option<&[u8]> {
+        // the buffer has been demuxed successfully on construction
+        Demuxer::new(self.buffer.as_slice()).ok()?.chunk(fourcc)
+    }
 
-impl DecoderWrapper {
-    pub fn new(
-        data: Pin<Box<webp::WebPData>>,
-        options: Pin<Box<webp::WebPAnimDecoderOptions>>,
-    ) -> Result<Self, Error> {
-        let decoder = unsafe { webp::WebPAnimDecoderNew(&*data, &*options) };
-        if decoder.is_null() {
-            return Err(Error::DecodeFailed);
+    /// Decode the frame at `index` (zero-based)
+    ///
+    /// Only the frames starting from the closest preceding keyframe are decoded. Seeking
+    /// is independent of the frame iterator ([`Decoder::into_iter`])
+    ///
+    /// Returns an [`Error`] if `index` is past the last frame, or in case of a decoding failure
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let mut decoder = Decoder::new(&buffer).unwrap();
+    ///
+    /// let frame = decoder.seek_to_frame(7).unwrap();
+    /// assert_eq!(frame.timestamp(), 320);
+    /// ```
+    pub fn seek_to_frame(&mut self, index: usize) -> Result<Frame, Error> {
+        let demuxer = Demuxer::new(self.buffer.as_slice())?;
+        let seeker = Seeker::get_or_init(&mut self.seeker, &demuxer, &self.options)?;
+
+        if index >= seeker.frames.len() {
+            return Err(Error::FrameIndexOutOfBounds(index, seeker.frames.len()));
         }
 
-        Ok(Self {
-            decoder,
-            data,
-            options,
-        })
-    }
-}
+        seeker.compositor.seek(&demuxer, &seeker.frames, index)?;
 
-impl Drop for DecoderWrapper {
-    fn drop(&mut self) {
-        unsafe { webp::WebPAnimDecoderDelete(self.decoder) };
+        log::trace!("Seeked to frame {}", index);
+
+        Ok(seeker.compositor.frame())
     }
-}
 
-impl<'a> IntoIterator for Decoder<'a> {
-    type Item = Frame;
+    /// Decode the frame that is displayed at `timestamp_ms` milliseconds from the start
+    /// of the animation
+    ///
+    /// Timestamps past the end of the animation return the last frame. See
+    /// [`Decoder::seek_to_frame`] for details
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let mut decoder = Decoder::new(&buffer).unwrap();
+    ///
+    /// // frames are displayed for 40ms each
+    /// let frame = decoder.frame_at(100).unwrap();
+    /// assert_eq!(frame.timestamp(), 120);
+    /// ```
+    pub fn frame_at(&mut self, timestamp_ms: i32) -> Result<Frame, Error> {
+        let index = match &self.seeker {
+            Some(seeker) => seeker.frames.index_at(timestamp_ms),
+            None => FrameIndex::new(&Demuxer::new(self.buffer.as_slice())?)?.index_at(timestamp_ms),
+        };
 
-    type IntoIter = DecoderIterator<'a>;
+        self.seek_to_frame(index)
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        DecoderIterator::new(self)
+    /// Returns an iterator that produces decoded [`Frame`]'s, or an [`Error`] if decoding
+    /// a frame fails
+    ///
+    /// Unlike [`Decoder::into_iter`], which silently ends on a decoding error, this makes a
+    /// partially corrupt animation distinguishable from a shorter one
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let decoder = Decoder::new(&buffer).unwrap();
+    ///
+    /// let frames = decoder.try_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    /// assert_eq!(frames.len(), 10);
+    /// ```
+    pub fn try_iter(self) -> TryDecoderIterator<'a> {
+        TryDecoderIterator::new(self)
     }
-}
 
-/// An iterator that produces decoded [`Frame`]'s from webp data
-pub struct DecoderIterator<'a> {
-    animation_decoder: Decoder<'a>,
-}
+    /// Returns an iterator that plays the animation [`AnimationInfo::loop_count`] times
+    ///
+    /// Frame timestamps keep increasing across loops
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let decoder = Decoder::new(&buffer).unwrap();
+    /// assert_eq!(decoder.info().loop_count, LoopCount::Infinite); // infinite
+    ///
+    /// let frame = decoder.looping_iter().nth(25).unwrap();
+    /// assert_eq!(frame.timestamp(), 1040);
+    /// ```
+    pub fn looping_iter(self) -> LoopingIterator<'a> {
+        let loop_count = self.info.loop_count;
+        self.looping_iter_with_count(loop_count)
+    }
 
-impl<'a> DecoderIterator<'a> {
-    fn new(animation_decoder: Decoder<'a>) -> Self {
-        Self { animation_decoder }
+    /// Returns an iterator that plays the animation `loop_count` times, overriding the loop
+    /// count of the file. `0` loops infinitely
+    ///
+    /// See [`Decoder::looping_iter`]
+    pub fn looping_iter_with_count(self, loop_count: u32) -> LoopingIterator<'a> {
+        LoopingIterator {
+            inner: self.try_iter(),
+            loop_count,
+            loops_done: 0,
+            finished: false,
+        }
     }
-}
 
-impl<'a> Iterator for DecoderIterator<'a> {
-    type Item = Frame;
+    /// Decode the next frame and return a reference to it, without copying the frame data
+    ///
+    /// The returned [`FrameRef`] points into the canvas of the decoder, and is valid until the
+    /// next frame is decoded. Use [`FrameRef::to_frame`] to keep it. Frames decoded here are
+    /// not produced again by iterators. Returns `None` after the last frame, or in case of a
+    /// decoding error
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let mut decoder = Decoder::new(&buffer).unwrap();
+    ///
+    /// while let Some(frame) = decoder.next_frame_ref() {
+    ///   assert_eq!(frame.data().len(), 400 * 400 * 4);
+    /// }
+    /// ```
+    pub fn next_frame_ref(&mut self) -> Option<FrameRef<'_>> {
+        let color_mode = self.options.color_mode;
+        let dimensions = self.dimensions();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.animation_decoder.has_more_frames() {
-            return None;
+        match self.decode_next()? {
+            Ok((timestamp, data)) => Some(FrameRef::new_from_decoder(
+                timestamp, color_mode, data, dimensions,
+            )),
+            Err(e) => {
+                log::warn!("Stopped decoding: {}", e);
+                None
+            }
         }
+    }
 
-        let mut output_buffer = std::ptr::null_mut();
-        let mut timestamp: i32 = 0;
+    /// Decode the next frame into `buffer`, and return its timestamp
+    ///
+    /// `buffer` must be exactly `width * height * color mode size` bytes. Returns `Ok(None)`
+    /// after the last frame. See also [`Decoder::next_frame_ref`]
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let mut decoder = Decoder::new(&buffer).unwrap();
+    ///
+    /// let mut canvas = vec![0u8; 400 * 400 * 4];
+    /// while let Some(_timestamp) = decoder.decode_next_into(&mut canvas).unwrap() {
+    ///   // upload `canvas`
+    /// }
+    /// ```
+    pub fn decode_next_into(&mut self, buffer: &mut [u8]) -> Result<Option<i32>, Error> {
+        let (width, height) = self.dimensions();
+        let expected = width as usize * height as usize * self.options.color_mode.size();
+        if buffer.len() != expected {
+            return Err(Error::BufferSizeFailed(expected, buffer.len()));
+        }
 
-        if unsafe {
-            webp::WebPAnimDecoderGetNext(
-                self.animation_decoder.decoder_wr.decoder,
-                &mut output_buffer,
-                &mut timestamp,
-            )
-        } != 1
-        {
-            // "False if any of the arguments are NULL, or if there is a parsing or decoding error, or if there are no more frames. Otherwise, returns true."
-            log::warn!("webp::WebPAnimDecoderGetNext did not return success - frame parsing failed, parsing/decoding error?");
-            return None;
+        match self.decode_next() {
+            Some(Ok((timestamp, data))) => {
+                buffer.copy_from_slice(data);
+                Ok(Some(timestamp))
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
         }
+    }
 
-        if output_buffer.is_null() {
-            log::error!("webp::WebPAnimDecoderGetNext returned null output ptr, can not decode a frame. This should not happen");
+    /// Rewind the decoder, so that decoding starts again from the first frame
+    pub fn reset(&mut self) {
+        if let Some(decoder_wr) = &self.decoder_wr {
+            unsafe { webp::WebPAnimDecoderReset(decoder_wr.decoder) };
+        }
+        self.position = Position::default();
+    }
+
+    fn remaining_frames(&self) -> usize {
+        (self.info.frame_count as usize).saturating_sub(self.position.index)
+    }
+
+    /// Decode the next frame onto the libwebp canvas, returning its timestamp and the canvas
+    ///
+    /// Returns `None` after the last frame. After an error, decoding ends
+    fn decode_next(&mut self) -> Option<Result<(i32, &[u8]), Error>> {
+        if self.remaining_frames() == 0 {
             return None;
         }
 
-        let info = &self.animation_decoder.info;
-        let opts = &self.animation_decoder.options;
+        let index = self.position.index;
+        let previous_timestamp = self.position.timestamp;
+
+        let (timestamp, canvas) = match self.decode_next_canvas() {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.position.index = self.info.frame_count as usize;
+
+                return Some(Err(match e {
+                    Error::DecodeFailed(error) => Error::FrameDecodeFailed {
+                        index,
+                        timestamp: previous_timestamp,
+                        error,
+                    },
+                    e => e,
+                }));
+            }
+        };
+
+        self.position = Position {
+            index: index + 1,
+            timestamp,
+        };
+
+        let color_mode = self.options.color_mode;
+        let needs_conversion = match self.decoder_wr {
+            Some(_) => canvas_mode(color_mode).1,
+            None => !matches!(color_mode, ColorMode::Rgba | ColorMode::RgbaPremultiplied),
+        };
+        let pixel_size = match needs_conversion {
+            true => 4,
+            false => color_mode.size(),
+        };
+
+        // the canvas is owned by the libwebp decoder (or compositor), and valid until the
+        // next call
+        let (width, height) = self.dimensions;
         let data = unsafe {
-            std::slice::from_raw_parts(
-                output_buffer,
-                info.canvas_width as usize * info.canvas_height as usize * opts.color_mode.size(),
-            )
+            std::slice::from_raw_parts(canvas, width as usize * height as usize * pixel_size)
+        };
+
+        let data = match needs_conversion {
+            true => {
+                convert(data, color_mode, &mut self.converted);
+                &self.converted[..]
+            }
+            false => data,
         };
 
         log::trace!(
@@ -274,28 +660,401 @@ impl<'a> Iterator for DecoderIterator<'a> {
             data.len()
         );
 
-        Some(Frame::new_from_decoder(
-            timestamp,
-            self.animation_decoder.options.color_mode,
-            data.to_vec(),
-            self.animation_decoder.dimensions(),
-        ))
+        Some(Ok((timestamp, data)))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::File;
-    use std::io::prelude::*;
+    /// Returns [`Error::DecodeFailed`] if libwebp fails to decode the frame
+    fn decode_next_canvas(&mut self) -> Result<(i32, *const u8), Error> {
+        let decoder = match &self.decoder_wr {
+            Some(decoder_wr) => decoder_wr.decoder,
+            None => return self.composite_next_canvas(),
+        };
 
-    #[test]
-    fn test_decoder_failure() {
-        let decoder = Decoder::new(&[]);
-        assert_eq!(decoder.unwrap_err(), Error::ZeroSizeBuffer);
+        let mut output_buffer = std::ptr::null_mut();
+        let mut timestamp: i32 = 0;
+        let start = Instant::now();
+
+        if unsafe { webp::WebPAnimDecoderHasMoreFrames(decoder) } == 0 {
+            log::warn!(
+                "webp::WebPAnimDecoderHasMoreFrames returned false before all frames were decoded"
+            );
+            return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+        }
+
+        if unsafe { webp::WebPAnimDecoderGetNext(decoder, &mut output_buffer, &mut timestamp) } != 1
+        {
+            // "False if any of the arguments are NULL, or if there is a parsing or decoding error, or if there are no more frames. Otherwise, returns true."
+            log::warn!("webp::WebPAnimDecoderGetNext did not return success - frame parsing failed, parsing/decoding error?");
+            return Err(Error::DecodeFailed(self.frame_decoding_error()));
+        }
+
+        if output_buffer.is_null() {
+            log::error!("webp::WebPAnimDecoderGetNext returned null output ptr, can not decode a frame. This should not happen");
+            return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+        }
+
+        self.options.limits.check_decode_time(start.elapsed())?;
+
+        Ok((timestamp, output_buffer))
+    }
+
+    /// Reason why libwebp failed to decode the next frame
+    fn frame_decoding_error(&self) -> DecodingError {
+        Demuxer::new(self.buffer.as_slice())
+            .ok()
+            .and_then(|demuxer| {
+                demuxer
+                    .fragment(self.position.index as u32)
+                    .map(|fragment| fragment.decoding_error())
+            })
+            .unwrap_or(DecodingError::BitstreamError)
+    }
+
+    /// Composite the next frame onto the canvas of the [`Compositor`], for cropped or scaled
+    /// decoding. The canvas is in Rgba (rgbA for premultiplied color modes)
+    fn composite_next_canvas(&mut self) -> Result<(i32, *const u8), Error> {
+        let demuxer = Demuxer::new(self.buffer.as_slice())?;
+        let seeker = Seeker::get_or_init(&mut self.seeker, &demuxer, &self.options)?;
+
+        let compositor = &mut seeker.compositor;
+        compositor.seek(&demuxer, &seeker.frames, self.position.index)?;
+
+        Ok((compositor.timestamp(), compositor.canvas().as_ptr()))
+    }
+}
+
+impl Decoder<'static> {
+    /// Construct a new decoder that takes ownership of webp `buffer`
+    ///
+    /// Accepts e.g. `Vec<u8>`, `Box<[u8]>` and `Arc<[u8]>`. The returned decoder is
+    /// `'static` and [`Send`]
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let buffer = std::fs::read("./data/animated.webp").unwrap();
+    /// let decoder = Decoder::new_owned(buffer).unwrap();
+    ///
+    /// let frames = std::thread::spawn(move || decoder.into_iter().count());
+    /// assert_eq!(frames.join().unwrap(), 10);
+    /// ```
+    pub fn new_owned<B: AsRef<[u8]> + Send + 'static>(buffer: B) -> Result<Self, Error> {
+        Decoder::new_owned_with_options(buffer, Default::default())
+    }
+
+    /// Construct a new decoder that takes ownership of webp `buffer`, with custom options
+    ///
+    /// See [`Decoder::new_owned`] and [`Decoder::new_with_options`]
+    pub fn new_owned_with_options<B: AsRef<[u8]> + Send + 'static>(
+        buffer: B,
+        options: DecoderOptions,
+    ) -> Result<Self, Error> {
+        Decoder::from_buffer(DecoderBuffer::Owned(Box::new(buffer)), options)
+    }
+}
+
+impl<'a> Debug for Decoder<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let info = &self.info;
+
+        write!(f, "Decoder {{ buffer: {}b, info: {{ w: {}, h: {}, loop_cnt: {}, bgcolor: 0x{:x}, frame_count: {} }} }}", self.buffer.as_slice().len(), info.canvas_width, info.canvas_height, info.loop_count, info.bgcolor, info.frame_count)
+    }
+}
+
+/// Input data of a [`Decoder`]
+enum DecoderBuffer<'a> {
+    Borrowed(&'a [u8]),
+    /// Boxed, so that the data stays in place even if the buffer type stores it inline
+    Owned(Box<dyn AsRef<[u8]> + Send>),
+}
+
+impl<'a> DecoderBuffer<'a> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            DecoderBuffer::Borrowed(buffer) => buffer,
+            DecoderBuffer::Owned(buffer) => (**buffer).as_ref(),
+        }
+    }
+}
+
+/// Sequential decoding position of a [`Decoder`]
+#[derive(Default)]
+struct Position {
+    /// Index of the next frame to be decoded
+    index: usize,
+    /// Timestamp of the last decoded frame
+    timestamp: i32,
+}
+
+/// Canvas state for random access, kept between seeks
+struct Seeker {
+    frames: FrameIndex,
+    compositor: Compositor,
+}
+
+impl Seeker {
+    /// Returns the seeker in `seeker`, creating it first if needed
+    fn get_or_init<'s>(
+        seeker: &'s mut Option<Seeker>,
+        demuxer: &Demuxer,
+        options: &DecoderOptions,
+    ) -> Result<&'s mut Seeker, Error> {
+        match seeker {
+            Some(seeker) => Ok(seeker),
+            None => Ok(seeker.get_or_insert(Seeker {
+                frames: FrameIndex::new(demuxer)?,
+                compositor: Compositor::new(demuxer.dimensions(), options)?,
+            })),
+        }
+    }
+}
+
+/// libwebp canvas mode for `color_mode`, and whether the canvas has to be converted into
+/// `color_mode` after decoding
+fn canvas_mode(color_mode: ColorMode) -> (webp::WEBP_CSP_MODE, bool) {
+    match color_mode {
+        ColorMode::Rgba => (webp::MODE_RGBA, false),
+        ColorMode::Bgra => (webp::MODE_BGRA, false),
+        ColorMode::RgbaPremultiplied => (webp::MODE_rgbA, false),
+        ColorMode::BgraPremultiplied => (webp::MODE_bgrA, false),
+        // not supported by WebPAnimDecoder, converted from rgbA / RGBA after decoding
+        ColorMode::ArgbPremultiplied => (webp::MODE_rgbA, true),
+        ColorMode::Rgb | ColorMode::Bgr | ColorMode::Rgb565 | ColorMode::Rgba4444 => {
+            (webp::MODE_RGBA, true)
+        }
+    }
+}
+
+struct DecoderWrapper {
+    decoder: *mut webp::WebPAnimDecoder,
+
+    #[allow(dead_code)]
+    data: Pin<Box<webp::WebPData>>,
+    #[allow(dead_code)]
+    options: Pin<Box<webp::WebPAnimDecoderOptions>>,
+}
+
+impl DecoderWrapper {
+    /// Create a libwebp decoder for `bytes`. The caller must keep `bytes` alive for as long
+    /// as the decoder
+    pub fn new(bytes: &[u8], options: &DecoderOptions) -> Result<Self, Error> {
+        let mut decoder_options = Box::pin(unsafe {
+            let mut options = mem::zeroed();
+
+            if webp::WebPAnimDecoderOptionsInit(&mut options) != 1 {
+                return Err(Error::OptionsInitFailed);
+            }
+
+            options
+        });
+
+        decoder_options.use_threads = if options.use_threads { 1 } else { 0 };
+        decoder_options.color_mode = canvas_mode(options.color_mode).0;
+
+        // pin data (& options above) because decoder takes reference to them
+        let data = Box::pin(webp::WebPData {
+            bytes: bytes.as_ptr(),
+            size: bytes.len(),
+        });
+
+        let decoder = unsafe { webp::WebPAnimDecoderNew(&*data, &*decoder_options) };
+        if decoder.is_null() {
+            // report a demuxing failure, otherwise libwebp does not tell the reason (most
+            // likely a failed allocation)
+            Demuxer::new(bytes)?;
+            return Err(Error::DecodeFailed(DecodingError::Unknown));
+        }
+
+        Ok(Self {
+            decoder,
+            data,
+            options: decoder_options,
+        })
+    }
+
+    pub fn info(&self) -> Result<webp::WebPAnimInfo, Error> {
+        unsafe {
+            let mut info = mem::zeroed();
+            if webp::WebPAnimDecoderGetInfo(self.decoder, &mut info) != 1 {
+                return Err(Error::DecoderGetInfoFailed);
+            }
+            Ok(info)
+        }
+    }
+}
+
+/// SAFETY: `WebPAnimDecoder` has no thread affinity, and the pointers held here refer only to
+/// data owned by (or borrowed for the lifetime of) the [`Decoder`] itself, so moving it to another
+/// thread is safe. It is not `Sync`, since decoding mutates the underlying decoder state
+unsafe impl Send for DecoderWrapper {}
+
+impl Drop for DecoderWrapper {
+    fn drop(&mut self) {
+        unsafe { webp::WebPAnimDecoderDelete(self.decoder) };
+    }
+}
+
+impl<'a> IntoIterator for Decoder<'a> {
+    type Item = Frame;
+
+    type IntoIter = DecoderIterator<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DecoderIterator {
+            inner: self.try_iter(),
+        }
+    }
+}
+
+/// An iterator that produces decoded [`Frame`]'s from webp data
+///
+/// The number of frames is known upfront ([`ExactSizeIterator`]). In case of a decoding
+/// error, iteration ends early. Use [`Decoder::try_iter`] to detect errors
+pub struct DecoderIterator<'a> {
+    inner: TryDecoderIterator<'a>,
+}
+
+impl<'a> Iterator for DecoderIterator<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next()? {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                log::warn!("Stopped decoding: {}", e);
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for DecoderIterator<'a> {}
+
+/// An iterator that produces decoded [`Frame`]'s from webp data, or an [`Error`]
+///
+/// Created with [`Decoder::try_iter`]. A decoding error is yielded once, after which the
+/// iteration ends
+pub struct TryDecoderIterator<'a> {
+    animation_decoder: Decoder<'a>,
+    /// Added to frame timestamps, increases on each [`TryDecoderIterator::rewind`]
+    timestamp_offset: i32,
+}
+
+impl<'a> TryDecoderIterator<'a> {
+    fn new(animation_decoder: Decoder<'a>) -> Self {
+        Self {
+            animation_decoder,
+            timestamp_offset: 0,
+        }
+    }
+
+    /// Start again from the first frame, continuing timestamps from the last decoded frame
+    fn rewind(&mut self) {
+        self.timestamp_offset = self
+            .timestamp_offset
+            .saturating_add(self.animation_decoder.position.timestamp);
+        self.animation_decoder.reset();
+    }
+}
+
+impl<'a> Iterator for TryDecoderIterator<'a> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let color_mode = self.animation_decoder.options.color_mode;
+        let dimensions = self.animation_decoder.dimensions();
+        let timestamp_offset = self.timestamp_offset;
+
+        let frame = self
+            .animation_decoder
+            .decode_next()?
+            .map(|(timestamp, data)| {
+                Frame::new_from_decoder(
+                    timestamp.saturating_add(timestamp_offset),
+                    color_mode,
+                    data.to_vec(),
+                    dimensions,
+                )
+            });
+
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.animation_decoder.remaining_frames();
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for TryDecoderIterator<'a> {}
+
+/// An iterator that produces decoded [`Frame`]'s repeatedly, honoring the loop count
+///
+/// Created with [`Decoder::looping_iter`] or [`Decoder::looping_iter_with_count`]. Timestamps
+/// keep increasing across loops. In case of a decoding error, iteration ends early
+pub struct LoopingIterator<'a> {
+    inner: TryDecoderIterator<'a>,
+    /// Number of loops to play, `0` means infinitely
+    loop_count: u32,
+    loops_done: u32,
+    finished: bool,
+}
+
+impl<'a> Iterator for LoopingIterator<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            match self.inner.next() {
+                Some(Ok(frame)) => return Some(frame),
+                Some(Err(e)) => {
+                    log::warn!("Stopped decoding: {}", e);
+                    self.finished = true;
+                }
+                None => {
+                    self.loops_done += 1;
+
+                    // an animation without frames would loop forever
+                    if self.inner.animation_decoder.info.frame_count == 0
+                        || (self.loop_count != 0 && self.loops_done >= self.loop_count)
+                    {
+                        self.finished = true;
+                    } else {
+                        log::trace!("Starting loop {}", self.loops_done + 1);
+                        self.inner.rewind();
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AnimParams, BlendMode, DisposeMode, Encoder, EncoderOptions, EncodingConfig, EncodingType,
+        FragmentRect, FrameBuilder, LoopCount,
+    };
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    #[test]
+    fn test_decoder_failure() {
+        let decoder = Decoder::new(&[]);
+        assert_eq!(decoder.unwrap_err(), Error::ZeroSizeBuffer);
 
         let decoder = Decoder::new(&[0x00, 0x01]);
-        assert_eq!(decoder.unwrap_err(), Error::DecodeFailed);
+        assert_eq!(
+            decoder.unwrap_err(),
+            Error::DecodeFailed(DecodingError::NotEnoughData)
+        );
 
         let mut buffer = Vec::new();
         File::open("./data/animated.webp")
@@ -304,7 +1063,10 @@ mod tests {
             .unwrap();
 
         let decoder = Decoder::new(&buffer[..1500]);
-        assert_eq!(decoder.unwrap_err(), Error::DecodeFailed);
+        assert_eq!(
+            decoder.unwrap_err(),
+            Error::DecodeFailed(DecodingError::NotEnoughData)
+        );
     }
 
     fn get_animated_buffer() -> Vec<u8> {
@@ -389,4 +1151,714 @@ mod tests {
             Error::TooLargeCanvas(16384, 12288, MAX_CANVAS_SIZE)
         );
     }
+
+    fn decode_with_limits(buffer: &[u8], limits: DecoderLimits) -> Result<Decoder<'_>, Error> {
+        Decoder::new_with_options(
+            buffer,
+            DecoderOptions {
+                limits,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_decoder_limits() {
+        let buffer = get_animated_buffer();
+
+        let limits = DecoderLimits {
+            max_canvas_pixels: 400 * 400,
+            max_frame_count: Some(10),
+            max_duration_ms: Some(400),
+            max_decoded_bytes: Some(10 * 400 * 400 * 4),
+            max_frame_decode_time: Some(Duration::from_secs(60)),
+        };
+        let decoder = decode_with_limits(&buffer, limits).unwrap();
+        assert_eq!(decoder.into_iter().count(), 10);
+
+        let assert_exceeded = |limits, error| {
+            assert_eq!(decode_with_limits(&buffer, limits).unwrap_err(), error);
+        };
+        assert_exceeded(
+            DecoderLimits {
+                max_canvas_pixels: 400 * 399,
+                ..limits
+            },
+            Error::TooLargeCanvas(400, 400, 400 * 399),
+        );
+        assert_exceeded(
+            DecoderLimits {
+                max_frame_count: Some(9),
+                ..limits
+            },
+            Error::TooManyFrames(10, 9),
+        );
+        assert_exceeded(
+            DecoderLimits {
+                max_duration_ms: Some(399),
+                ..limits
+            },
+            Error::TooLongDuration(400, 399),
+        );
+        assert_exceeded(
+            DecoderLimits {
+                max_decoded_bytes: Some(6_399_999),
+                ..limits
+            },
+            Error::TooManyDecodedBytes(6_400_000, 6_399_999),
+        );
+
+        let limits = DecoderLimits {
+            max_frame_decode_time: Some(Duration::ZERO),
+            ..limits
+        };
+        let decoder = decode_with_limits(&buffer, limits).unwrap();
+        assert_eq!(decoder.into_iter().count(), 0);
+
+        let mut decoder = decode_with_limits(&buffer, limits).unwrap();
+        assert!(matches!(
+            decoder.seek_to_frame(3).unwrap_err(),
+            Error::FrameDecodeTimeExceeded(_, Duration::ZERO)
+        ));
+    }
+
+    #[test]
+    fn test_owned_decoder() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let buffer = get_animated_buffer();
+        let expected: Vec<_> = Decoder::new(&buffer).unwrap().into_iter().collect();
+
+        let decoders = [
+            Decoder::new_owned(buffer.clone()).unwrap(),
+            Decoder::new_owned(buffer.clone().into_boxed_slice()).unwrap(),
+            Decoder::new_owned(std::sync::Arc::<[u8]>::from(buffer)).unwrap(),
+        ];
+
+        for decoder in decoders {
+            assert_send(&decoder);
+
+            let frames = std::thread::spawn(move || decoder.into_iter().collect::<Vec<_>>())
+                .join()
+                .unwrap();
+
+            assert_eq!(frames.len(), expected.len());
+            for (f1, f2) in frames.iter().zip(expected.iter()) {
+                assert_eq!(f1.timestamp(), f2.timestamp());
+                assert!(f1.data() == f2.data());
+            }
+        }
+
+        assert_eq!(
+            Decoder::new_owned(Vec::new()).unwrap_err(),
+            Error::ZeroSizeBuffer
+        );
+    }
+
+    #[test]
+    fn test_animation_info() {
+        let buffer = get_animated_buffer();
+        let decoder = Decoder::new(&buffer).unwrap();
+
+        assert_eq!(
+            decoder.info(),
+            AnimationInfo {
+                dimensions: (400, 400),
+                loop_count: LoopCount::Infinite,
+                background_color: [255, 255, 255, 255],
+                frame_count: 10,
+                duration_ms: 400,
+                has_alpha: true,
+                is_animated: true,
+                has_icc_profile: false,
+                has_exif: false,
+                has_xmp: false,
+                file_size: 4934,
+            }
+        );
+
+        let mut iter = decoder.into_iter();
+        assert_eq!(iter.len(), 10);
+        iter.next().unwrap();
+        assert_eq!(iter.size_hint(), (9, Some(9)));
+        assert_eq!(iter.count(), 9);
+    }
+
+    #[test]
+    fn test_try_iter() {
+        let mut buffer = get_animated_buffer();
+
+        let frames: Vec<_> = Decoder::new(&buffer).unwrap().try_iter().collect();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame.is_ok()));
+
+        // set an invalid alpha compression method for the fifth frame
+        let alph = {
+            let demuxer = Demuxer::new(&buffer).unwrap();
+            let fragment = demuxer.fragments().nth(4).unwrap();
+            assert_eq!(&fragment.data()[0..4], b"ALPH");
+            fragment.data().as_ptr() as usize - buffer.as_ptr() as usize
+        };
+        buffer[alph + 8] = 0x03;
+
+        let mut iter = Decoder::new(&buffer).unwrap().try_iter();
+        assert_eq!(iter.len(), 10);
+        for _ in 0..4 {
+            iter.next().unwrap().unwrap();
+        }
+        // the reason is the one libwebp reports for decoding the frame on its own
+        let error = Demuxer::new(&buffer)
+            .unwrap()
+            .fragment(4)
+            .unwrap()
+            .decoding_error();
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            Error::FrameDecodeFailed {
+                index: 4,
+                timestamp: 160,
+                error,
+            }
+        );
+        assert_eq!(iter.len(), 0);
+        assert!(iter.next().is_none());
+
+        // the infallible iterator ends early
+        assert_eq!(Decoder::new(&buffer).unwrap().into_iter().count(), 4);
+    }
+
+    #[test]
+    fn test_looping_iter() {
+        let buffer = get_animated_buffer();
+        let expected: Vec<_> = Decoder::new(&buffer).unwrap().into_iter().collect();
+
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        decoder.reset();
+
+        let frames: Vec<_> = decoder.looping_iter_with_count(2).collect();
+        assert_eq!(frames.len(), 20);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.timestamp(), (i as i32 + 1) * 40);
+            assert!(frame.data() == expected[i % 10].data());
+        }
+
+        // infinite
+        let decoder = Decoder::new(&buffer).unwrap();
+        assert_eq!(decoder.looping_iter().nth(1000).unwrap().timestamp(), 40040);
+
+        // loop count of the file
+        let mut encoder = Encoder::new_with_options(
+            (4, 4),
+            EncoderOptions {
+                anim_params: AnimParams {
+                    loop_count: LoopCount::new(3).unwrap(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        encoder.add_frame(&[0u8; 4 * 4 * 4], 0).unwrap();
+        encoder.add_frame(&[255u8; 4 * 4 * 4], 50).unwrap();
+        let webp_data = encoder.finalize(100).unwrap();
+
+        let decoder = Decoder::new(&webp_data).unwrap();
+        assert_eq!(decoder.info().loop_count.to_webp(), 3);
+        let timestamps: Vec<_> = decoder.looping_iter().map(|f| f.timestamp()).collect();
+        assert_eq!(timestamps, [50, 100, 150, 200, 250, 300]);
+    }
+
+    #[test]
+    fn test_zero_copy_decoding() {
+        let buffer = get_animated_buffer();
+        let expected: Vec<_> = Decoder::new(&buffer).unwrap().into_iter().collect();
+
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        for frame in &expected[..3] {
+            let frame_ref = decoder.next_frame_ref().unwrap();
+            assert_eq!(frame_ref.timestamp(), frame.timestamp());
+            assert_eq!(frame_ref.dimensions(), (400, 400));
+            assert!(frame_ref.data() == frame.data());
+            assert!(frame_ref.to_frame().data() == frame.data());
+        }
+
+        let mut canvas = vec![0u8; 400 * 400 * 4];
+        assert_eq!(
+            decoder.decode_next_into(&mut canvas[1..]).unwrap_err(),
+            Error::BufferSizeFailed(640_000, 639_999)
+        );
+        assert_eq!(decoder.decode_next_into(&mut canvas).unwrap(), Some(160));
+        assert!(canvas == expected[3].data());
+
+        // iteration continues from the current frame
+        let iter = decoder.into_iter();
+        assert_eq!(iter.len(), 6);
+        assert_eq!(iter.map(|f| f.timestamp()).next(), Some(200));
+
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        let mut count = 0;
+        while let Some(timestamp) = decoder.decode_next_into(&mut canvas).unwrap() {
+            assert_eq!(timestamp, expected[count].timestamp());
+            assert!(canvas == expected[count].data());
+            count += 1;
+        }
+        assert_eq!(count, 10);
+        assert!(decoder.next_frame_ref().is_none());
+
+        decoder.reset();
+        assert_eq!(decoder.next_frame_ref().unwrap().timestamp(), 40);
+    }
+
+    /// An animation with semi-transparent, blended frames
+    fn translucent_animation() -> crate::WebPData {
+        let (width, height) = (32, 32);
+        let mut encoder = Encoder::new_with_options(
+            (width, height),
+            EncoderOptions {
+                kmin: 3,
+                kmax: 5,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for i in 0..8 {
+            let mut data = Vec::new();
+            for y in 0..height {
+                for x in 0..width {
+                    let inside = (i * 3..i * 3 + 8).contains(&x) && (10..18).contains(&y);
+                    let alpha = if inside {
+                        255 - i as u8 * 20
+                    } else {
+                        (x * 8) as u8
+                    };
+                    data.extend_from_slice(&[(y * 8) as u8, 200, i as u8 * 30, alpha]);
+                }
+            }
+            encoder.add_frame(&data, i as i32 * 50).unwrap();
+        }
+
+        encoder.finalize(400).unwrap()
+    }
+
+    #[test]
+    fn test_premultiplied_color_modes() {
+        let buffer = translucent_animation();
+
+        let decode = |color_mode| {
+            let options = DecoderOptions {
+                color_mode,
+                ..Default::default()
+            };
+            let frames: Vec<_> = Decoder::new_with_options(&buffer, options)
+                .unwrap()
+                .into_iter()
+                .collect();
+            assert_eq!(frames.len(), 8);
+            assert_eq!(frames[0].color_mode(), color_mode);
+            frames
+        };
+
+        let straight = decode(ColorMode::Rgba);
+        let rgba = decode(ColorMode::RgbaPremultiplied);
+        let bgra = decode(ColorMode::BgraPremultiplied);
+        let argb = decode(ColorMode::ArgbPremultiplied);
+
+        for i in 0..8 {
+            let pixels = straight[i].data().chunks_exact(4);
+            let rgba = rgba[i].data().chunks_exact(4);
+            let bgra = bgra[i].data().chunks_exact(4);
+            let argb = argb[i].data().chunks_exact(4);
+
+            for (((s, p), bgra), argb) in pixels.zip(rgba).zip(bgra).zip(argb) {
+                assert_eq!(p[3], s[3]);
+                if s[3] == 255 {
+                    assert_eq!(p, s);
+                }
+                assert!(p[0..3].iter().zip(s).all(|(c, s)| *c <= *s && *c <= p[3]));
+                assert_eq!(bgra, [p[2], p[1], p[0], p[3]]);
+                assert_eq!(argb, [p[3], p[0], p[1], p[2]]);
+            }
+        }
+
+        // the animation has semi-transparent pixels
+        let pixels = rgba[2].data().chunks_exact(4);
+        assert!(pixels.clone().any(|p| p[3] > 0 && p[3] < 255));
+    }
+
+    #[test]
+    fn test_packed_color_modes() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+
+        let decode = |color_mode| {
+            let options = DecoderOptions {
+                color_mode,
+                ..Default::default()
+            };
+            let frames: Vec<_> = Decoder::new_with_options(&buffer, options)
+                .unwrap()
+                .into_iter()
+                .collect();
+            assert_eq!(frames[0].color_mode(), color_mode);
+            frames
+        };
+
+        let rgba = decode(ColorMode::Rgba);
+        let rgb565 = decode(ColorMode::Rgb565);
+        let rgba4444 = decode(ColorMode::Rgba4444);
+        assert_eq!(rgb565.len(), rgba.len());
+        assert_eq!(rgba4444.len(), rgba.len());
+
+        for ((rgba, rgb565), rgba4444) in rgba.iter().zip(&rgb565).zip(&rgba4444) {
+            assert_eq!(rgb565.timestamp(), rgba.timestamp());
+            assert_eq!(rgb565.data().len(), 400 * 400 * 2);
+            assert_eq!(rgba4444.data().len(), 400 * 400 * 2);
+
+            let pixels = rgba.data().chunks_exact(4);
+            let packed = rgb565
+                .data()
+                .chunks_exact(2)
+                .zip(rgba4444.data().chunks_exact(2));
+            for (p, (rgb565, rgba4444)) in pixels.zip(packed) {
+                let (r, g, b, a) = (p[0] as u16, p[1] as u16, p[2] as u16, p[3] as u16);
+                let value = ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3);
+                assert_eq!(rgb565, value.to_be_bytes());
+                let value = ((r >> 4) << 12) | ((g >> 4) << 8) | ((b >> 4) << 4) | (a >> 4);
+                assert_eq!(rgba4444, value.to_be_bytes());
+            }
+        }
+
+        let mut decoder = Decoder::new_with_options(
+            &buffer,
+            DecoderOptions {
+                color_mode: ColorMode::Rgb565,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut output = vec![0; 400 * 400 * 2];
+        assert_eq!(decoder.decode_next_into(&mut output), Ok(Some(40)));
+        assert_eq!(output, rgb565[0].data());
+
+        assert_seeking_matches_iterator(&buffer, ColorMode::Rgba4444);
+
+        let rgb = decode(ColorMode::Rgb);
+        let bgr = decode(ColorMode::Bgr);
+        assert_eq!(rgb.len(), rgba.len());
+        assert_eq!(bgr.len(), rgba.len());
+
+        for ((rgba, rgb), bgr) in rgba.iter().zip(&rgb).zip(&bgr) {
+            assert_eq!(rgb.timestamp(), rgba.timestamp());
+            assert_eq!(rgb.data().len(), 400 * 400 * 3);
+            assert_eq!(bgr.data().len(), 400 * 400 * 3);
+
+            let pixels = rgba.data().chunks_exact(4);
+            let unpacked = rgb.data().chunks_exact(3).zip(bgr.data().chunks_exact(3));
+            for (p, (rgb, bgr)) in pixels.zip(unpacked) {
+                assert_eq!(rgb, [p[0], p[1], p[2]]);
+                assert_eq!(bgr, [p[2], p[1], p[0]]);
+            }
+        }
+
+        assert_seeking_matches_iterator(&buffer, ColorMode::Bgr);
+    }
+
+    #[test]
+    fn test_cropped_decoding() {
+        for file in ["./data/animated.webp", "./data/example.webp"] {
+            let buffer = std::fs::read(file).unwrap();
+            let expected: Vec<_> = Decoder::new(&buffer).unwrap().into_iter().collect();
+            let (width, _) = expected[0].dimensions();
+
+            let crop = CropRect {
+                left: 13,
+                top: 20,
+                width: 101,
+                height: 57,
+            };
+            let decoder = Decoder::new_with_options(
+                &buffer,
+                DecoderOptions {
+                    crop: Some(crop),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(decoder.dimensions(), (101, 57));
+
+            let frames: Vec<_> = decoder.into_iter().collect();
+            assert_eq!(frames.len(), expected.len());
+
+            // cropping without scaling is exact
+            for (frame, expected) in frames.iter().zip(&expected) {
+                assert_eq!(frame.timestamp(), expected.timestamp());
+                assert_eq!(frame.dimensions(), (101, 57));
+
+                for (y, row) in frame.data().chunks_exact(101 * 4).enumerate() {
+                    let start = ((y + 20) * width as usize + 13) * 4;
+                    assert_eq!(row, &expected.data()[start..start + 101 * 4]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_scaled_decoding() {
+        let buffer = std::fs::read("./data/example.webp").unwrap();
+        let expected: Vec<_> = Decoder::new(&buffer).unwrap().into_iter().collect();
+        let (width, height) = expected[0].dimensions();
+
+        let options = || DecoderOptions {
+            output_size: Some((width / 4, height / 4)),
+            ..Default::default()
+        };
+        let frames: Vec<_> = Decoder::new_with_options(&buffer, options())
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(frames.len(), expected.len());
+
+        for (frame, expected) in frames.iter().zip(&expected) {
+            assert_eq!(frame.timestamp(), expected.timestamp());
+            assert_eq!(frame.dimensions(), (width / 4, height / 4));
+
+            // compare against 4x4 box downscaled full size frame
+            let mut difference = 0;
+            for (i, pixel) in frame.data().chunks_exact(4).enumerate() {
+                let (x, y) = (i % (width as usize / 4) * 4, i / (width as usize / 4) * 4);
+                for (c, value) in pixel.iter().enumerate() {
+                    let sum: u32 = (0..16)
+                        .map(|j| {
+                            let index = ((y + j / 4) * width as usize + x + j % 4) * 4 + c;
+                            expected.data()[index] as u32
+                        })
+                        .sum();
+                    difference += (*value as i32 - (sum / 16) as i32).unsigned_abs() as u64;
+                }
+            }
+            let average = difference as f64 / frame.data().len() as f64;
+            assert!(average < 2.0, "average difference {}", average);
+        }
+
+        let mut decoder = Decoder::new_with_options(&buffer, options()).unwrap();
+        let last = frames.len() - 1;
+        for index in [last, 0, last / 2, last / 2 + 1, 1] {
+            let frame = decoder.seek_to_frame(index).unwrap();
+            assert!(
+                frame.data() == frames[index].data(),
+                "frame {} differs",
+                index
+            );
+        }
+        assert_eq!(decoder.next_frame_ref().unwrap().data(), frames[0].data());
+        decoder.reset();
+        assert_eq!(decoder.into_iter().count(), frames.len());
+
+        // crop and upscale
+        let decoder = Decoder::new_with_options(
+            &buffer,
+            DecoderOptions {
+                crop: Some(CropRect {
+                    left: 10,
+                    top: 10,
+                    width: 20,
+                    height: 10,
+                }),
+                output_size: Some((80, 40)),
+                color_mode: ColorMode::Bgra,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let frame = decoder.into_iter().next().unwrap();
+        assert_eq!(frame.data().len(), 80 * 40 * 4);
+    }
+
+    #[test]
+    fn test_invalid_geometry() {
+        let buffer = get_animated_buffer();
+        let decode = |crop, output_size| {
+            Decoder::new_with_options(
+                &buffer,
+                DecoderOptions {
+                    crop,
+                    output_size,
+                    ..Default::default()
+                },
+            )
+            .map(|decoder| decoder.dimensions())
+        };
+
+        let crop = CropRect {
+            left: 300,
+            top: 0,
+            width: 101,
+            height: 10,
+        };
+        assert_eq!(decode(Some(crop), None), Err(Error::InvalidCrop(crop)));
+        let crop = CropRect { width: 0, ..crop };
+        assert_eq!(decode(Some(crop), None), Err(Error::InvalidCrop(crop)));
+        assert_eq!(
+            decode(None, Some((0, 10))),
+            Err(Error::InvalidOutputSize(0, 10))
+        );
+        assert_eq!(
+            decode(None, Some((4000, 4000))),
+            Err(Error::TooLargeCanvas(4000, 4000, MAX_CANVAS_SIZE))
+        );
+        assert_eq!(decode(None, Some((1, 1))), Ok((1, 1)));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let buffer = std::fs::read("./data/metadata.webp").unwrap();
+        let decoder = Decoder::new(&buffer).unwrap();
+
+        let info = decoder.info();
+        assert!(info.has_icc_profile && info.has_exif && info.has_xmp);
+
+        let icc = decoder.icc_profile().unwrap();
+        assert_eq!(icc.len(), 48);
+        assert_eq!(&icc[16..20], b"RGB ");
+
+        let exif = decoder.exif().unwrap();
+        assert_eq!(&exif[0..4], b"II*\0");
+        assert_eq!(&exif[26..40], b"webp-animation");
+
+        let xmp = decoder.xmp().unwrap();
+        assert!(xmp.starts_with(b"<x:xmpmeta"));
+        assert_eq!(decoder.chunk(b"XMP "), Some(xmp));
+
+        // metadata is a slice of the input buffer
+        let start = exif.as_ptr() as usize - buffer.as_ptr() as usize;
+        assert_eq!(&buffer[start - 8..start - 4], b"EXIF");
+
+        assert_eq!(decoder.chunk(b"ANIM"), None);
+        assert_eq!(decoder.chunk(b"abcd"), None);
+        assert_eq!(decoder.into_iter().count(), 30);
+
+        let buffer = get_animated_buffer();
+        let decoder = Decoder::new_owned(buffer).unwrap();
+        assert_eq!(decoder.icc_profile(), None);
+        assert_eq!(decoder.exif(), None);
+        assert_eq!(decoder.xmp(), None);
+    }
+
+    fn assert_seeking_matches_iterator(buffer: &[u8], color_mode: ColorMode) {
+        let options = || DecoderOptions {
+            color_mode,
+            ..Default::default()
+        };
+        let expected: Vec<_> = Decoder::new_with_options(buffer, options())
+            .unwrap()
+            .into_iter()
+            .collect();
+
+        let mut decoder = Decoder::new_with_options(buffer, options()).unwrap();
+        let last = expected.len() - 1;
+        let order = [last, 0, last / 2, last / 2 + 1, 1, last, last - 1, 2, 2];
+
+        for index in order {
+            let frame = decoder.seek_to_frame(index).unwrap();
+            assert_eq!(frame.timestamp(), expected[index].timestamp());
+            assert!(
+                frame.data() == expected[index].data(),
+                "frame {} differs",
+                index
+            );
+        }
+
+        // seeking does not affect iteration
+        assert_eq!(decoder.into_iter().count(), expected.len());
+    }
+
+    #[test]
+    fn test_seek_to_frame() {
+        let animated = get_animated_buffer();
+        let example = std::fs::read("./data/example.webp").unwrap();
+        let translucent = translucent_animation();
+
+        assert_seeking_matches_iterator(&animated, ColorMode::Rgba);
+        assert_seeking_matches_iterator(&example, ColorMode::Rgba);
+        assert_seeking_matches_iterator(&translucent, ColorMode::Rgba);
+        assert_seeking_matches_iterator(&translucent, ColorMode::RgbaPremultiplied);
+        assert_seeking_matches_iterator(&translucent, ColorMode::BgraPremultiplied);
+        assert_seeking_matches_iterator(&translucent, ColorMode::ArgbPremultiplied);
+
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        assert_eq!(
+            decoder.seek_to_frame(10).unwrap_err(),
+            Error::FrameIndexOutOfBounds(10, 10)
+        );
+    }
+
+    #[test]
+    fn test_frame_at() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let mut decoder = Decoder::new(&buffer).unwrap();
+
+        assert_eq!(decoder.frame_at(0).unwrap().timestamp(), 40);
+        assert_eq!(decoder.frame_at(39).unwrap().timestamp(), 40);
+        assert_eq!(decoder.frame_at(40).unwrap().timestamp(), 80);
+        assert_eq!(decoder.frame_at(399).unwrap().timestamp(), 400);
+        assert_eq!(decoder.frame_at(10_000).unwrap().timestamp(), 400);
+        assert_eq!(decoder.frame_at(-5).unwrap().timestamp(), 40);
+    }
+
+    #[test]
+    fn test_max_duration_frames() {
+        // 130 frames of the maximum duration add up past i32::MAX
+        let mut builder = FrameBuilder::new((2, 2)).unwrap();
+        let config = EncodingConfig {
+            encoding_type: EncodingType::new_lossless(),
+            ..Default::default()
+        };
+        for i in 0..130u8 {
+            let rect = FragmentRect {
+                x_offset: 0,
+                y_offset: 0,
+                width: 2,
+                height: 2,
+            };
+            builder
+                .add_fragment(
+                    &[i; 2 * 2 * 4],
+                    rect,
+                    0xff_ffff,
+                    BlendMode::NoBlend,
+                    DisposeMode::None,
+                    &config,
+                )
+                .unwrap();
+        }
+        let buffer = builder.finalize().unwrap();
+
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        assert_eq!(
+            decoder.seek_to_frame(127).unwrap().timestamp(),
+            128 * 0xff_ffff
+        );
+        assert_eq!(decoder.seek_to_frame(129).unwrap().timestamp(), i32::MAX);
+        assert_eq!(decoder.frame_at(i32::MAX - 1).unwrap().data()[0], 128);
+        assert_eq!(decoder.frame_at(i32::MAX).unwrap().data()[0], 129);
+
+        let mut decoder = Decoder::new(&buffer).unwrap();
+        assert_eq!(decoder.frame_at(0).unwrap().timestamp(), 0xff_ffff);
+
+        let poster = crate::poster_at(&buffer, 129, Default::default()).unwrap();
+        assert_eq!(poster.timestamp(), i32::MAX);
+
+        let scaled = Decoder::new_with_options(
+            &buffer,
+            DecoderOptions {
+                output_size: Some((1, 1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let timestamps: Vec<_> = scaled.into_iter().map(|f| f.timestamp()).collect();
+        assert_eq!(timestamps.len(), 130);
+        assert_eq!(timestamps[129], i32::MAX);
+    }
 }
@@ -0,0 +1,582 @@
+use std::{fmt::Debug, marker::PhantomData, mem, slice};
+
+use libwebp_sys as webp;
+
+use crate::{DecodingError, Error};
+
+pub(crate) const RIFF_HEADER_SIZE: usize = 12;
+pub(crate) const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Parsing state of a (possibly partial) webp stream
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum DemuxState {
+    /// Not enough data to parse the file header yet
+    ParsingHeader,
+    /// Header has been parsed, frames may still be incoming
+    ParsedHeader,
+    /// Whole stream has been parsed
+    Done,
+}
+
+/// A demuxer giving access to the raw, not yet decoded, frames of webp data
+///
+/// Where [`Decoder`](crate::Decoder) produces fully composited canvases, `Demuxer` shows
+/// how the animation is structured in the file: each [`Fragment`] covers a (possibly partial)
+/// area of the canvas, and is blended and disposed according to its own settings
+///
+/// ```rust
+/// use webp_animation::prelude::*;
+///
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let demuxer = Demuxer::new(&buffer).unwrap();
+///
+/// for fragment in demuxer.fragments() {
+///   println!("frame {}: {:?} at {:?}", fragment.index(), fragment.dimensions(), fragment.offset());
+///   assert_eq!(fragment.duration(), 40);
+/// }
+/// ```
+pub struct Demuxer<'a> {
+    demux: *mut webp::WebPDemuxer,
+    state: DemuxState,
+    _buffer: PhantomData<&'a [u8]>,
+}
+
+impl<'a> Demuxer<'a> {
+    /// Construct a new demuxer from webp `buffer`
+    ///
+    /// Returns an [`Error`] in case of a parsing failure (e.g. malformed input)
+    pub fn new(buffer: &'a [u8]) -> Result<Self, Error> {
+        if buffer.is_empty() {
+            return Err(Error::ZeroSizeBuffer);
+        }
+
+        let data = webp::WebPData {
+            bytes: buffer.as_ptr(),
+            size: buffer.len(),
+        };
+
+        let demux = unsafe { webp::WebPDemux(&data) };
+        if demux.is_null() {
+            // libwebp does not tell whether the data is malformed or truncated, but a partial
+            // demux does
+            return Err(match Self::new_partial(buffer) {
+                Err(e) => e,
+                Ok(_) => Error::DecodeFailed(DecodingError::NotEnoughData),
+            });
+        }
+
+        Ok(Self {
+            demux,
+            state: DemuxState::Done,
+            _buffer: PhantomData,
+        })
+    }
+
+    /// Demux a webp `buffer` which may be truncated
+    ///
+    /// Returns `Ok(None)` if there is not enough data to parse the file header yet
+    pub(crate) fn new_partial(buffer: &'a [u8]) -> Result<Option<Self>, Error> {
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let data = webp::WebPData {
+            bytes: buffer.as_ptr(),
+            size: buffer.len(),
+        };
+
+        let mut state = webp::WEBP_DEMUX_PARSE_ERROR;
+        let demux = unsafe { webp::WebPDemuxPartial(&data, &mut state) };
+
+        let state = match state {
+            webp::WEBP_DEMUX_PARSING_HEADER => DemuxState::ParsingHeader,
+            webp::WEBP_DEMUX_PARSED_HEADER => DemuxState::ParsedHeader,
+            webp::WEBP_DEMUX_DONE => DemuxState::Done,
+            _ => {
+                if !demux.is_null() {
+                    unsafe { webp::WebPDemuxDelete(demux) };
+                }
+                return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+            }
+        };
+
+        if demux.is_null() {
+            // libwebp reports a malformed first chunk as still parsing the header. If
+            // the RIFF header is there in full, the stream can not be valid anymore
+            if buffer.len() >= RIFF_HEADER_SIZE + CHUNK_HEADER_SIZE
+                && &buffer[0..4] == b"RIFF"
+                && &buffer[8..12] == b"WEBP"
+            {
+                return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+            }
+
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            demux,
+            state,
+            _buffer: PhantomData,
+        }))
+    }
+
+    pub(crate) fn state(&self) -> DemuxState {
+        self.state
+    }
+
+    /// Returns canvas dimensions (`width`, `height`)
+    pub fn dimensions(&self) -> (u32, u32) {
+        (
+            self.get(webp::WEBP_FF_CANVAS_WIDTH),
+            self.get(webp::WEBP_FF_CANVAS_HEIGHT),
+        )
+    }
+
+    /// Returns the number of frames
+    pub fn frame_count(&self) -> u32 {
+        self.get(webp::WEBP_FF_FRAME_COUNT)
+    }
+
+    /// Animation information, same as reported by libwebp `WebPAnimDecoderGetInfo`
+    pub(crate) fn anim_info(&self) -> webp::WebPAnimInfo {
+        let mut info: webp::WebPAnimInfo = unsafe { mem::zeroed() };
+        info.canvas_width = self.get(webp::WEBP_FF_CANVAS_WIDTH);
+        info.canvas_height = self.get(webp::WEBP_FF_CANVAS_HEIGHT);
+        info.loop_count = self.get(webp::WEBP_FF_LOOP_COUNT);
+        info.bgcolor = self.get(webp::WEBP_FF_BACKGROUND_COLOR);
+        info.frame_count = self.frame_count();
+        info
+    }
+
+    /// VP8X feature flags (`ICCP_FLAG`, `EXIF_FLAG`, ...)
+    pub(crate) fn format_flags(&self) -> u32 {
+        self.get(webp::WEBP_FF_FORMAT_FLAGS)
+    }
+
+    /// Total duration of the frames parsed so far, in milliseconds
+    pub(crate) fn duration(&self) -> u64 {
+        (0..self.frame_count())
+            .filter_map(|index| self.fragment(index))
+            .map(|fragment| fragment.header.duration.max(0) as u64)
+            .sum()
+    }
+
+    /// Iterate over all frames as [`Fragment`]'s, in display order
+    pub fn fragments(&self) -> Fragments<'_, 'a> {
+        Fragments {
+            demuxer: self,
+            index: 0,
+            previous: None,
+            previous_was_keyframe: false,
+        }
+    }
+
+    /// Returns the payload of the first chunk with id `fourcc` (e.g. `b"ICCP"`), if any
+    ///
+    /// The returned slice borrows the input buffer. Only metadata (`ICCP`, `EXIF`, `XMP `)
+    /// and unknown chunks are available
+    pub fn chunk(&self, fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+        let mut iter = unsafe { mem::zeroed() };
+        if unsafe { webp::WebPDemuxGetChunk(self.demux, fourcc.as_ptr() as *const _, 1, &mut iter) }
+            == 0
+        {
+            return None;
+        }
+
+        let data = unsafe { slice::from_raw_parts(iter.chunk.bytes, iter.chunk.size) };
+
+        unsafe { webp::WebPDemuxReleaseChunkIterator(&mut iter) };
+
+        Some(data)
+    }
+
+    fn get(&self, feature: webp::WebPFormatFeature) -> u32 {
+        unsafe { webp::WebPDemuxGetI(self.demux, feature) }
+    }
+
+    /// Get the frame at `index` (zero-based)
+    pub(crate) fn fragment(&self, index: u32) -> Option<RawFragment<'a>> {
+        let mut iter = unsafe { mem::zeroed() };
+        if unsafe { webp::WebPDemuxGetFrame(self.demux, index as i32 + 1, &mut iter) } == 0 {
+            return None;
+        }
+
+        let fragment = RawFragment {
+            header: FrameHeader {
+                index,
+                x_offset: iter.x_offset as u32,
+                y_offset: iter.y_offset as u32,
+                width: iter.width as u32,
+                height: iter.height as u32,
+                duration: iter.duration,
+                dispose_background: iter.dispose_method == webp::WEBP_MUX_DISPOSE_BACKGROUND,
+                blend: iter.blend_method == webp::WEBP_MUX_BLEND,
+                has_alpha: iter.has_alpha != 0,
+            },
+            complete: iter.complete != 0,
+            data: unsafe { slice::from_raw_parts(iter.fragment.bytes, iter.fragment.size) },
+        };
+
+        unsafe { webp::WebPDemuxReleaseIterator(&mut iter) };
+
+        Some(fragment)
+    }
+}
+
+impl<'a> Debug for Demuxer<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (width, height) = self.dimensions();
+
+        write!(
+            f,
+            "Demuxer {{ w: {}, h: {}, frame_count: {} }}",
+            width,
+            height,
+            self.frame_count()
+        )
+    }
+}
+
+impl<'a> Drop for Demuxer<'a> {
+    fn drop(&mut self) {
+        unsafe { webp::WebPDemuxDelete(self.demux) };
+    }
+}
+
+/// A single (compressed) animation frame as stored in the webp container
+#[derive(Clone, Debug)]
+pub(crate) struct RawFragment<'a> {
+    pub header: FrameHeader,
+    pub complete: bool,
+    pub data: &'a [u8],
+}
+
+impl<'a> RawFragment<'a> {
+    /// Decode the fragment on its own to find out why libwebp failed to decode it, as the
+    /// animation decoder does not report the reason. Decoding errors of the bitstream are
+    /// the most common cause, and also reported if the fragment decodes fine
+    pub fn decoding_error(&self) -> DecodingError {
+        let mut config: webp::WebPDecoderConfig = unsafe { mem::zeroed() };
+        if unsafe { webp::WebPInitDecoderConfig(&mut config) } == 0 {
+            return DecodingError::InvalidParam;
+        }
+
+        let status = unsafe { webp::WebPDecode(self.data.as_ptr(), self.data.len(), &mut config) };
+        unsafe { webp::WebPFreeDecBuffer(&mut config.output) };
+
+        DecodingError::check(status)
+            .err()
+            .unwrap_or(DecodingError::BitstreamError)
+    }
+}
+
+/// An iterator over the [`Fragment`]'s of a [`Demuxer`]
+pub struct Fragments<'d, 'a> {
+    demuxer: &'d Demuxer<'a>,
+    index: u32,
+    previous: Option<FrameHeader>,
+    previous_was_keyframe: bool,
+}
+
+impl<'d, 'a> Iterator for Fragments<'d, 'a> {
+    type Item = Fragment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw = self.demuxer.fragment(self.index)?;
+        let header = raw.header;
+
+        let keyframe = is_keyframe(
+            &header,
+            self.previous.as_ref(),
+            self.previous_was_keyframe,
+            self.demuxer.dimensions(),
+        );
+
+        self.index += 1;
+        self.previous = Some(header);
+        self.previous_was_keyframe = keyframe;
+
+        Some(Fragment { raw, keyframe })
+    }
+}
+
+/// A single compressed frame of an animation, as stored in the file
+///
+/// Produced by [`Demuxer::fragments`]
+#[derive(Clone, Debug)]
+pub struct Fragment<'a> {
+    raw: RawFragment<'a>,
+    keyframe: bool,
+}
+
+impl<'a> Fragment<'a> {
+    /// Returns the index of the frame (zero-based)
+    pub fn index(&self) -> usize {
+        self.raw.header.index as usize
+    }
+
+    /// Returns the position (`x`, `y`) of the fragment on the canvas
+    pub fn offset(&self) -> (u32, u32) {
+        (self.raw.header.x_offset, self.raw.header.y_offset)
+    }
+
+    /// Returns dimensions of the fragment (`width`, `height`)
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.raw.header.width, self.raw.header.height)
+    }
+
+    /// Returns the display duration of the frame, in milliseconds
+    pub fn duration(&self) -> i32 {
+        self.raw.header.duration
+    }
+
+    /// Returns how the fragment is drawn over the previous canvas
+    pub fn blend_mode(&self) -> BlendMode {
+        match self.raw.header.blend {
+            true => BlendMode::Blend,
+            false => BlendMode::NoBlend,
+        }
+    }
+
+    /// Returns what happens to the fragment area after the frame has been displayed
+    pub fn dispose_mode(&self) -> DisposeMode {
+        match self.raw.header.dispose_background {
+            true => DisposeMode::Background,
+            false => DisposeMode::None,
+        }
+    }
+
+    /// Returns true if the fragment contains alpha
+    pub fn has_alpha(&self) -> bool {
+        self.raw.header.has_alpha
+    }
+
+    /// Returns true if the frame can be decoded without any of the previous frames
+    pub fn is_keyframe(&self) -> bool {
+        self.keyframe
+    }
+
+    /// Returns the compression format of the fragment
+    pub fn format(&self) -> BitstreamFormat {
+        // an optional ALPH chunk is only used with lossy compression
+        match self.raw.data.get(0..4) {
+            Some(b"VP8L") => BitstreamFormat::Vp8l,
+            _ => BitstreamFormat::Vp8,
+        }
+    }
+
+    /// Returns the compressed data of the fragment (`ALPH`, `VP8 ` or `VP8L` chunks)
+    pub fn data(&self) -> &'a [u8] {
+        self.raw.data
+    }
+}
+
+/// How a [`Fragment`] is drawn over the previous canvas
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BlendMode {
+    /// Alpha-blend the fragment with the previous canvas
+    Blend,
+    /// Overwrite the fragment area of the previous canvas
+    NoBlend,
+}
+
+/// What happens to the area of a [`Fragment`] after the frame has been displayed
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DisposeMode {
+    /// Leave the canvas as is
+    None,
+    /// Clear the fragment area to transparent
+    Background,
+}
+
+/// Compression format of a [`Fragment`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BitstreamFormat {
+    /// Lossy compression, optionally with a separate alpha channel
+    Vp8,
+    /// Lossless compression
+    Vp8l,
+}
+
+/// Placement and timing of a [`Fragment`] on the animation canvas
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct FrameHeader {
+    pub index: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub width: u32,
+    pub height: u32,
+    pub duration: i32,
+    pub dispose_background: bool,
+    pub blend: bool,
+    pub has_alpha: bool,
+}
+
+impl FrameHeader {
+    fn is_full_frame(&self, canvas: (u32, u32)) -> bool {
+        (self.width, self.height) == canvas
+    }
+}
+
+/// Whether the frame can be decoded without knowing the previous canvas state
+///
+/// Mirrors `IsKeyFrame` of libwebp `anim_decode.c`
+pub(crate) fn is_keyframe(
+    header: &FrameHeader,
+    previous: Option<&FrameHeader>,
+    previous_was_keyframe: bool,
+    canvas: (u32, u32),
+) -> bool {
+    let previous = match previous {
+        Some(previous) if header.index > 0 => previous,
+        _ => return true,
+    };
+
+    if (!header.has_alpha || !header.blend) && header.is_full_frame(canvas) {
+        return true;
+    }
+
+    previous.dispose_background && (previous.is_full_frame(canvas) || previous_was_keyframe)
+}
+
+/// Keyframe and timing information of all frames in an animation, for random access
+pub(crate) struct FrameIndex {
+    keyframes: Vec<bool>,
+    /// Presentation timestamp (end of display) of each frame
+    timestamps: Vec<i32>,
+}
+
+impl FrameIndex {
+    pub fn new(demuxer: &Demuxer) -> Result<Self, Error> {
+        let frame_count = demuxer.frame_count() as usize;
+
+        let mut keyframes = Vec::with_capacity(frame_count);
+        let mut timestamps = Vec::with_capacity(frame_count);
+        let mut timestamp: i32 = 0;
+
+        for fragment in demuxer.fragments() {
+            timestamp = timestamp.saturating_add(fragment.duration());
+
+            keyframes.push(fragment.is_keyframe());
+            timestamps.push(timestamp);
+        }
+
+        if keyframes.len() != frame_count {
+            return Err(Error::DecodeFailed(DecodingError::BitstreamError));
+        }
+
+        Ok(Self {
+            keyframes,
+            timestamps,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    /// Index of the closest keyframe at or before `index`
+    pub fn keyframe_before(&self, index: usize) -> usize {
+        (0..=index).rev().find(|i| self.keyframes[*i]).unwrap_or(0)
+    }
+
+    /// Presentation timestamp of the frame before `index`, zero for the first frame
+    pub fn timestamp_before(&self, index: usize) -> i32 {
+        match index {
+            0 => 0,
+            _ => self.timestamps[index - 1],
+        }
+    }
+
+    /// Index of the frame being displayed at `timestamp_ms`. Timestamps past the end of
+    /// the animation map to the last frame
+    pub fn index_at(&self, timestamp_ms: i32) -> usize {
+        self.timestamps
+            .iter()
+            .position(|end| *end > timestamp_ms)
+            .unwrap_or_else(|| self.len().saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoder, EncoderOptions};
+
+    #[test]
+    fn test_demuxer_failure() {
+        assert_eq!(Demuxer::new(&[]).unwrap_err(), Error::ZeroSizeBuffer);
+        assert_eq!(
+            Demuxer::new(&[0x00, 0x01]).unwrap_err(),
+            Error::DecodeFailed(DecodingError::NotEnoughData)
+        );
+    }
+
+    #[test]
+    fn test_demuxer_fragments() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let demuxer = Demuxer::new(&buffer).unwrap();
+        assert_eq!(demuxer.dimensions(), (400, 400));
+        assert_eq!(demuxer.frame_count(), 10);
+
+        let fragments: Vec<_> = demuxer.fragments().collect();
+        assert_eq!(fragments.len(), 10);
+
+        let first = &fragments[0];
+        assert_eq!(first.index(), 0);
+        assert_eq!(first.offset(), (0, 0));
+        assert_eq!(first.dimensions(), (400, 400));
+        assert_eq!(first.blend_mode(), BlendMode::NoBlend);
+        assert!(!first.has_alpha());
+        assert!(first.is_keyframe());
+
+        let second = &fragments[1];
+        assert_eq!(second.index(), 1);
+        assert_eq!(second.offset(), (180, 180));
+        assert_eq!(second.dimensions(), (46, 48));
+        assert_eq!(second.duration(), 40);
+        assert_eq!(second.blend_mode(), BlendMode::Blend);
+        assert_eq!(second.dispose_mode(), DisposeMode::None);
+        assert!(second.has_alpha());
+        assert!(!second.is_keyframe());
+        assert_eq!(second.format(), BitstreamFormat::Vp8);
+        assert_eq!(second.data().len(), 448);
+
+        // fragment data is a slice of the input buffer
+        let start = second.data().as_ptr() as usize - buffer.as_ptr() as usize;
+        assert_eq!(&buffer[start..start + 4], b"ALPH");
+
+        let buffer = std::fs::read("./data/example.webp").unwrap();
+        let demuxer = Demuxer::new(&buffer).unwrap();
+        let last = demuxer.fragments().last().unwrap();
+        assert_eq!(last.index(), 29);
+        assert_eq!(last.offset(), (448, 448));
+        assert_eq!(last.dimensions(), (32, 32));
+        assert_eq!(last.format(), BitstreamFormat::Vp8l);
+    }
+
+    #[test]
+    fn test_demuxer_keyframes() {
+        let mut encoder = Encoder::new_with_options(
+            (4, 4),
+            EncoderOptions {
+                kmin: 1,
+                kmax: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for i in 0..6u8 {
+            encoder
+                .add_frame(&[i * 40; 4 * 4 * 4], i as i32 * 10)
+                .unwrap();
+        }
+        let webp_data = encoder.finalize(60).unwrap();
+
+        let demuxer = Demuxer::new(&webp_data).unwrap();
+        let keyframes: Vec<_> = demuxer.fragments().map(|f| f.is_keyframe()).collect();
+        assert_eq!(keyframes.len(), 6);
+        assert!(keyframes[0]);
+        assert!(keyframes[1..].contains(&true));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{BitstreamFormat, BlendMode, Decoder, Demuxer, DisposeMode, Error, FragmentRect};
+
+#[allow(unused_imports)]
+use crate::{Encoder, EncoderOptions}; // needed by docs
+
+/// Statistics of an encoded animation, returned by [`Encoder::finalize_with_report`]
+///
+/// ```rust
+/// use webp_animation::prelude::*;
+///
+/// let mut encoder = Encoder::new_with_options((64, 32), EncoderOptions {
+///     measure_psnr: true,
+///     encoding_config: Some(EncodingConfig::new_lossy(75.)),
+///     ..Default::default()
+/// }).unwrap();
+/// encoder.add_frame(&[255u8; 64 * 32 * 4], 0).unwrap();
+/// encoder.add_frame(&[0u8; 64 * 32 * 4], 100).unwrap();
+/// let (webp_data, report) = encoder.finalize_with_report(200).unwrap();
+///
+/// assert_eq!(report.file_size, webp_data.len());
+/// assert_eq!(report.frames.len(), 2);
+/// assert!(report.frames[0].keyframe);
+/// assert!(report.frames[1].psnr.unwrap()[0] > 30.);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodeReport {
+    /// Statistics of each frame in the output
+    pub frames: Vec<FrameStats>,
+    /// Size of the webp data in bytes
+    pub file_size: usize,
+}
+
+/// Statistics of a single encoded frame, see [`EncodeReport`]
+///
+/// A frame identical to the previous one is merged into it by libwebp, extending its
+/// duration, so there can be fewer frames than were added
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameStats {
+    /// Timestamp of the frame, as given to [`Encoder::add_frame`]
+    pub timestamp: i32,
+    /// Display duration of the frame, in milliseconds
+    pub duration: i32,
+    /// Size of the compressed frame in bytes, without the frame header
+    pub coded_size: usize,
+    /// Whether the frame was encoded lossless
+    pub lossless: bool,
+    /// Sub-rectangle of the canvas the frame was encoded as
+    pub rect: FragmentRect,
+    /// Whether the frame can be decoded without any of the previous frames
+    pub keyframe: bool,
+    /// How the frame is drawn over the previous canvas
+    pub blend_mode: BlendMode,
+    /// What happens to the frame area after the frame has been displayed
+    pub dispose_mode: DisposeMode,
+    /// PSNR of the frame against the input, in dB for each channel in Rgba order
+    ///
+    /// Measured by this crate, not taken from libwebp statistics (`WebPAuxStats`), so the
+    /// values are not comparable with the ones reported by `cwebp`. The output animation is
+    /// decoded again, and the whole composited canvas is compared against the frame given
+    /// to [`Encoder::add_frame`], converted to Rgba. The error of Rgb channels of fully transparent
+    /// input pixels is left out, as the encoder may change their color, but those pixels
+    /// still count in the average. Channels without any error report `99`, the values are
+    /// capped at `99` as well
+    ///
+    /// Only measured if [`EncoderOptions::measure_psnr`] is set
+    pub psnr: Option<[f32; 4]>,
+}
+
+impl EncodeReport {
+    /// Collect the statistics of encoded `data`. `sources` are the Rgba input frames with
+    /// their timestamps if PSNR is measured, empty otherwise
+    pub(crate) fn new(
+        data: &[u8],
+        first_timestamp: i32,
+        sources: &[(i32, Vec<u8>)],
+    ) -> Result<Self, Error> {
+        let demuxer = Demuxer::new(data)?;
+
+        let decoded = match sources.is_empty() {
+            true => Vec::new(),
+            false => Decoder::new(data)?
+                .try_iter()
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        let mut timestamp = first_timestamp;
+        let mut frames = Vec::new();
+
+        for fragment in demuxer.fragments() {
+            let (x_offset, y_offset) = fragment.offset();
+            let (width, height) = fragment.dimensions();
+
+            let source = sources.iter().find(|(ts, _)| *ts == timestamp);
+            let psnr = match (source, decoded.get(fragment.index())) {
+                (Some((_, source)), Some(frame)) => Some(psnr(source, frame.data())),
+                _ => None,
+            };
+
+            frames.push(FrameStats {
+                timestamp,
+                duration: fragment.duration(),
+                coded_size: fragment.data().len(),
+                lossless: fragment.format() == BitstreamFormat::Vp8l,
+                rect: FragmentRect {
+                    x_offset,
+                    y_offset,
+                    width,
+                    height,
+                },
+                keyframe: fragment.is_keyframe(),
+                blend_mode: fragment.blend_mode(),
+                dispose_mode: fragment.dispose_mode(),
+                psnr,
+            });
+
+            timestamp = timestamp.saturating_add(fragment.duration());
+        }
+
+        Ok(Self {
+            frames,
+            file_size: data.len(),
+        })
+    }
+
+    /// Sum of the coded sizes of all frames, in bytes
+    pub fn coded_size(&self) -> usize {
+        self.frames.iter().map(|frame| frame.coded_size).sum()
+    }
+
+    /// Number of frames encoded lossless
+    pub fn lossless_count(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.lossless).count()
+    }
+
+    /// Number of keyframes
+    pub fn keyframe_count(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.keyframe).count()
+    }
+
+    /// Mean of the per-frame PSNR of each channel, if measured
+    pub fn mean_psnr(&self) -> Option<[f32; 4]> {
+        let mut sum = [0.; 4];
+        for frame in &self.frames {
+            for (sum, psnr) in sum.iter_mut().zip(frame.psnr?.iter()) {
+                *sum += psnr;
+            }
+        }
+
+        let count = self.frames.len() as f32;
+        Some([
+            sum[0] / count,
+            sum[1] / count,
+            sum[2] / count,
+            sum[3] / count,
+        ])
+    }
+}
+
+/// PSNR of each channel of Rgba `decoded` against Rgba `source`, see [`FrameStats::psnr`]
+fn psnr(source: &[u8], decoded: &[u8]) -> [f32; 4] {
+    let mut sse = [0u64; 4];
+    for (source, decoded) in source.chunks_exact(4).zip(decoded.chunks_exact(4)) {
+        // the encoder is free to change the color of invisible pixels
+        let channels = match source[3] {
+            0 => 3..4,
+            _ => 0..4,
+        };
+        for c in channels {
+            let diff = source[c] as i64 - decoded[c] as i64;
+            sse[c] += (diff * diff) as u64;
+        }
+    }
+
+    let max = 255. * 255. * (source.len() / 4) as f64;
+    let mut psnr = [0.; 4];
+    for (psnr, &sse) in psnr.iter_mut().zip(sse.iter()) {
+        *psnr = match sse {
+            0 => 99.,
+            _ => (10. * (max / sse as f64).log10()).min(99.) as f32,
+        };
+    }
+    psnr
+}
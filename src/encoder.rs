@@ -1,8 +1,20 @@
-use std::{mem, pin::Pin, ptr};
+use std::{
+    any::Any,
+    ffi::CStr,
+    marker::PhantomData,
+    mem,
+    os::raw::c_int,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    ptr, slice,
+};
 
 use libwebp_sys as webp;
 
-use crate::{ColorMode, ConfigContainer, EncoderOptions, EncodingConfig, Error, WebPData};
+use crate::{
+    threaded_encoder::ThreadedEncoder, ColorMode, ConfigContainer, EncodeReport, EncoderOptions,
+    EncodingConfig, EncodingError, Error, Metadata, WebPData,
+};
 
 #[allow(unused_imports)]
 use crate::LossyEncodingConfig; // for docs
@@ -87,7 +99,19 @@ pub struct Encoder {
     frame: PictureWrapper,
     options: EncoderOptions,
     previous_timestamp: i32,
+    first_timestamp: i32,
     encoding_config: Option<ConfigContainer>,
+    /// Rgba copies of the frames with their timestamps, if [`EncoderOptions::measure_psnr`]
+    sources: Vec<(i32, Vec<u8>)>,
+    frame_count: usize,
+    /// Boxed, as libwebp keeps a pointer to it in `frame`
+    progress: Option<Box<ProgressState>>,
+    /// Set when a frame was cancelled while libwebp was encoding it. libwebp has already
+    /// extended the previous frame by then, and can not undo it
+    cancelled: bool,
+    /// Set if [`EncoderOptions::threads`] is above one, frames are then encoded by it
+    /// instead of `encoder_wr`
+    threaded: Option<ThreadedEncoder>,
 }
 
 impl Encoder {
@@ -105,6 +129,22 @@ impl Encoder {
             return Err(Error::DimensionsMustbePositive);
         }
 
+        options.validate()?;
+        if options.threads > 1 {
+            for (field, value) in [
+                ("allow_mixed", options.allow_mixed),
+                ("minimize_size", options.minimize_size),
+            ] {
+                if value {
+                    return Err(Error::InvalidConfigField {
+                        field,
+                        value: value.to_string(),
+                        allowed: "false when threads is above 1".to_string(),
+                    });
+                }
+            }
+        }
+
         let enc_options = convert_options(&options)?;
         let encoder_wr = EncoderWrapper::new(dimensions, enc_options)?;
 
@@ -115,7 +155,16 @@ impl Encoder {
             options: options.clone(),
             frame: PictureWrapper::new(dimensions)?,
             previous_timestamp: -1,
+            first_timestamp: 0,
             encoding_config: None,
+            sources: Vec::new(),
+            frame_count: 0,
+            progress: None,
+            cancelled: false,
+            threaded: match options.threads {
+                0 | 1 => None,
+                _ => Some(ThreadedEncoder::new(dimensions, &options)?),
+            },
         };
 
         if let Some(config) = options.encoding_config {
@@ -134,7 +183,44 @@ impl Encoder {
     ///   calculated as "timestamp of next frame - timestamp of this frame".
     ///   Hence, timestamps should be in non-decreasing order.
     pub fn add_frame(&mut self, data: &[u8], timestamp_ms: i32) -> Result<(), Error> {
-        self.add_frame_internal(data, timestamp_ms, None)
+        let view = self.frame_view(data)?;
+        self.add_frame_internal(&view, timestamp_ms, None)
+    }
+
+    /// Add a new frame to be encoded from a buffer with row padding
+    ///
+    /// `stride` is the distance between the starts of two rows in bytes, at least `width` *
+    /// [`ColorMode::size`]. The last row does not need to be padded. See
+    /// [`Encoder::add_frame`] for `data` and `timestamp` explanations
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// // rows of 3 Rgba pixels, aligned to 16 bytes
+    /// let mut encoder = Encoder::new((3, 2)).unwrap();
+    /// encoder.add_frame_strided(&[255u8; 16 + 3 * 4], 16, 0).unwrap();
+    /// ```
+    pub fn add_frame_strided(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        timestamp_ms: i32,
+    ) -> Result<(), Error> {
+        let view = FrameView {
+            data,
+            stride,
+            offset: (0, 0),
+            dimensions: self.dimensions(),
+        };
+        self.add_frame_view(&view, timestamp_ms)
+    }
+
+    /// Add a new frame to be encoded from a [`FrameView`] into a larger buffer
+    ///
+    /// Pixels are passed to libwebp in place, unless the [`ColorMode`] needs a conversion.
+    /// See [`Encoder::add_frame`] for `timestamp` explanation
+    pub fn add_frame_view(&mut self, view: &FrameView, timestamp_ms: i32) -> Result<(), Error> {
+        self.add_frame_internal(view, timestamp_ms, None)
     }
 
     /// Add a new frame to be encoded with special per-frame configuration ([`EncodingConfig`])
@@ -146,15 +232,42 @@ impl Encoder {
         timestamp_ms: i32,
         config: &EncodingConfig,
     ) -> Result<(), Error> {
-        self.add_frame_internal(data, timestamp_ms, Some(config))
+        let view = self.frame_view(data)?;
+        self.add_frame_internal(&view, timestamp_ms, Some(config))
+    }
+
+    /// View of a tightly packed frame `data`, which must hold exactly one frame
+    fn frame_view<'a>(&self, data: &'a [u8]) -> Result<FrameView<'a>, Error> {
+        let (width, height) = self.dimensions();
+        let stride = width as usize * self.options.color_mode.size();
+
+        let expected_len = stride * height as usize;
+        if data.len() != expected_len {
+            return Err(Error::BufferSizeFailed(expected_len, data.len()));
+        }
+
+        Ok(FrameView {
+            data,
+            stride,
+            offset: (0, 0),
+            dimensions: (width, height),
+        })
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        self.frame.dimensions()
     }
 
     fn add_frame_internal(
         &mut self,
-        data: &[u8],
+        view: &FrameView,
         timestamp: i32,
         config: Option<&EncodingConfig>,
     ) -> Result<(), Error> {
+        if self.cancelled {
+            return Err(Error::Cancelled);
+        }
+
         if timestamp <= self.previous_timestamp {
             return Err(Error::TimestampMustBeHigherThanPrevious(
                 timestamp,
@@ -162,7 +275,40 @@ impl Encoder {
             ));
         }
 
-        self.frame.set_data(data, self.options.color_mode)?;
+        if let Some(threaded) = &mut self.threaded {
+            // the default of libwebp, used for a null config
+            let default_config = EncodingConfig::new_lossy(75.);
+            let config = config
+                .or(self.options.encoding_config.as_ref())
+                .unwrap_or(&default_config);
+            // the frame is only encoded after the next one is added, fail on this one
+            config.to_config_container()?;
+
+            // the picture is only needed for the report, importing it is not free
+            if self.options.measure_psnr {
+                self.frame.set_data(view, self.options.color_mode)?;
+            }
+
+            if let Some(progress) = &mut self.progress {
+                progress.start_frame(self.frame_count, timestamp);
+                if !progress.report(0) {
+                    return Err(progress.take_error());
+                }
+            }
+
+            threaded.add(view, timestamp, config)?;
+            self.frame_added(view, timestamp);
+            return Ok(());
+        }
+
+        self.frame.set_data(view, self.options.color_mode)?;
+
+        if let Some(progress) = &mut self.progress {
+            progress.start_frame(self.frame_count, timestamp);
+            if !progress.report(0) {
+                return Err(progress.take_error());
+            }
+        }
 
         if unsafe {
             webp::WebPAnimEncoderAdd(
@@ -182,18 +328,89 @@ impl Encoder {
             )
         } == 0
         {
-            return Err(Error::EncoderAddFailed);
+            if let Some(progress) = &mut self.progress {
+                if progress.stopped() {
+                    self.cancelled = true;
+                    return Err(progress.take_error());
+                }
+            }
+
+            return Err(Error::EncoderAddFailed {
+                error: self.frame.error(),
+                message: self.encoder_wr.error_message(),
+            });
         }
 
+        self.frame_added(view, timestamp);
+        Ok(())
+    }
+
+    fn frame_added(&mut self, view: &FrameView, timestamp: i32) {
+        self.frame_count += 1;
+        if self.previous_timestamp == -1 {
+            self.first_timestamp = timestamp;
+        }
         self.previous_timestamp = timestamp;
 
+        if self.options.measure_psnr {
+            self.sources.push((timestamp, self.frame.rgba()));
+        }
+
         log::trace!(
             "Add a frame at timestamp {}ms, {} bytes",
             timestamp,
-            data.len()
+            view.data.len()
         );
+    }
 
-        Ok(())
+    /// Set a `callback` to follow the encoding progress, and to cancel it
+    ///
+    /// The callback is called with `percent` of `0` when a frame is added, and then on each
+    /// progress update of libwebp. libwebp may encode a frame several times to pick the
+    /// smallest result, so the percentage can restart from `0` within a frame
+    ///
+    /// Returning [`ControlFlow::Break`] aborts adding the frame with [`Error::Cancelled`].
+    /// If the frame is cancelled on the first call (`percent` of `0`), before libwebp has
+    /// started on it, the frame is not added and the encoder can still be used. Once libwebp
+    /// is encoding the frame, cancelling leaves the animation incomplete: every later call
+    /// returns [`Error::Cancelled`] and the encoder can only be dropped. The same holds if
+    /// the callback panics
+    ///
+    /// With [`EncoderOptions::threads`] above one, frames are encoded on the workers later
+    /// on, and the callback is only called with `percent` of `0`
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let mut encoder = Encoder::new((64, 64)).unwrap();
+    /// encoder.set_progress_callback(|progress| match progress.frame_index {
+    ///     0 => ControlFlow::Continue,
+    ///     _ => ControlFlow::Break,
+    /// });
+    ///
+    /// encoder.add_frame(&[0u8; 64 * 64 * 4], 0).unwrap();
+    /// assert_eq!(
+    ///     encoder.add_frame(&[255u8; 64 * 64 * 4], 100).unwrap_err(),
+    ///     webp_animation::Error::Cancelled
+    /// );
+    /// ```
+    pub fn set_progress_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(Progress) -> ControlFlow + 'static,
+    {
+        let mut progress = Box::new(ProgressState {
+            callback: Box::new(callback),
+            progress: Progress {
+                frame_index: 0,
+                timestamp: 0,
+                percent: 0,
+            },
+            cancelled: false,
+            panic: None,
+        });
+        self.frame
+            .set_progress_hook(&mut *progress as *mut ProgressState as *mut _);
+        self.progress = Some(progress);
     }
 
     /// Sets the default encoding config
@@ -208,7 +425,47 @@ impl Encoder {
     /// Will encode the stream and return encoded bytes in a [`WebPData`] upon success
     ///
     /// `timestamp_ms` behaves as in [`Encoder::add_frame`], and determines the duration of the last frame
-    pub fn finalize(self, timestamp_ms: i32) -> Result<WebPData, Error> {
+    ///
+    /// If [`EncoderOptions::metadata`] has been set, the chunks are written into the output
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let mut encoder = Encoder::new_with_options((4, 4), EncoderOptions {
+    ///     metadata: Metadata {
+    ///         xmp: Some(b"<x:xmpmeta/>".to_vec()),
+    ///         ..Default::default()
+    ///     },
+    ///     ..Default::default()
+    /// }).unwrap();
+    /// encoder.add_frame(&[0u8; 4 * 4 * 4], 0).unwrap();
+    /// let webp_data = encoder.finalize(100).unwrap();
+    ///
+    /// let decoder = Decoder::new(&webp_data).unwrap();
+    /// assert_eq!(decoder.xmp(), Some(&b"<x:xmpmeta/>"[..]));
+    /// ```
+    pub fn finalize(mut self, timestamp_ms: i32) -> Result<WebPData, Error> {
+        self.assemble(timestamp_ms)
+    }
+
+    /// Like [`Encoder::finalize`], but also returns an [`EncodeReport`] with statistics of
+    /// each encoded frame
+    ///
+    /// Frames are only decoded for the report if [`EncoderOptions::measure_psnr`] is set
+    pub fn finalize_with_report(
+        mut self,
+        timestamp_ms: i32,
+    ) -> Result<(WebPData, EncodeReport), Error> {
+        let data = self.assemble(timestamp_ms)?;
+        let report = EncodeReport::new(&data, self.first_timestamp, &self.sources)?;
+        Ok((data, report))
+    }
+
+    fn assemble(&mut self, timestamp_ms: i32) -> Result<WebPData, Error> {
+        if self.cancelled {
+            return Err(Error::Cancelled);
+        }
+
         if self.previous_timestamp == -1 {
             // -1 = no frames added
             return Err(Error::NoFramesAdded);
@@ -221,6 +478,11 @@ impl Encoder {
             ));
         }
 
+        if let Some(threaded) = self.threaded.take() {
+            // metadata is written by the frame builder
+            return threaded.finish(timestamp_ms);
+        }
+
         if unsafe {
             webp::WebPAnimEncoderAdd(
                 self.encoder_wr.encoder,
@@ -230,14 +492,24 @@ impl Encoder {
             )
         } == 0
         {
-            return Err(Error::EncoderAddFailed);
+            // without a frame, libwebp reports the reason in the error message only
+            return Err(Error::EncoderAddFailed {
+                error: EncodingError::Unknown,
+                message: self.encoder_wr.error_message(),
+            });
         }
 
         let mut data = WebPData::new();
 
         if unsafe { webp::WebPAnimEncoderAssemble(self.encoder_wr.encoder, data.inner_ref()) } == 0
         {
-            return Err(Error::EncoderAssmebleFailed);
+            return Err(Error::EncoderAssmebleFailed(
+                self.encoder_wr.error_message(),
+            ));
+        }
+
+        if self.options.metadata.chunks().next().is_some() {
+            data = write_metadata(&data, &self.options.metadata)?;
         }
 
         log::trace!(
@@ -250,6 +522,85 @@ impl Encoder {
     }
 }
 
+/// Progress of an [`Encoder`], see [`Encoder::set_progress_callback`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Index of the frame being added (zero-based)
+    pub frame_index: usize,
+    /// Timestamp of the frame being added, in milliseconds
+    pub timestamp: i32,
+    /// Encoding progress of the frame, between `0` and `100`
+    pub percent: u32,
+}
+
+/// Whether to continue encoding, returned by the [`Encoder::set_progress_callback`] callback
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ControlFlow {
+    /// Continue encoding
+    Continue,
+    /// Cancel encoding, the encoder returns [`Error::Cancelled`]
+    Break,
+}
+
+struct ProgressState {
+    callback: Box<dyn FnMut(Progress) -> ControlFlow>,
+    progress: Progress,
+    cancelled: bool,
+    /// Panic of the callback, resumed once libwebp has returned
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl ProgressState {
+    fn start_frame(&mut self, frame_index: usize, timestamp: i32) {
+        self.progress.frame_index = frame_index;
+        self.progress.timestamp = timestamp;
+        self.cancelled = false;
+    }
+
+    /// Call the callback, returns false if encoding should stop
+    fn report(&mut self, percent: u32) -> bool {
+        if self.stopped() {
+            // keep aborting, libwebp may still report progress of the current frame
+            return false;
+        }
+
+        self.progress.percent = percent;
+        let progress = self.progress;
+        let callback = &mut self.callback;
+
+        // unwinding into libwebp is not allowed
+        match panic::catch_unwind(AssertUnwindSafe(|| callback(progress))) {
+            Ok(ControlFlow::Continue) => true,
+            Ok(ControlFlow::Break) => {
+                self.cancelled = true;
+                false
+            }
+            Err(panic) => {
+                self.panic = Some(panic);
+                false
+            }
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.cancelled || self.panic.is_some()
+    }
+
+    /// Error to return after `report` asked to stop. Resumes a panic of the callback
+    fn take_error(&mut self) -> Error {
+        if let Some(panic) = self.panic.take() {
+            panic::resume_unwind(panic);
+        }
+        Error::Cancelled
+    }
+}
+
+/// libwebp progress hook calling the [`ProgressState`] in `user_data` of the picture
+extern "C" fn progress_hook(percent: c_int, picture: *const webp::WebPPicture) -> c_int {
+    let progress = unsafe { &mut *((*picture).user_data as *mut ProgressState) };
+    progress.report(percent.max(0) as u32) as c_int
+}
+
 fn convert_options(
     options: &EncoderOptions,
 ) -> Result<Pin<Box<webp::WebPAnimEncoderOptions>>, Error> {
@@ -261,17 +612,135 @@ fn convert_options(
         enc_options
     });
 
-    enc_options.anim_params.loop_count = options.anim_params.loop_count;
+    enc_options.anim_params = options.anim_params.to_webp();
 
     enc_options.minimize_size = if options.minimize_size { 1 } else { 0 };
-    enc_options.kmin = options.kmin as i32;
-    enc_options.kmax = options.kmax as i32;
+    let (kmin, kmax) = options.keyframe_distances();
+    if kmin != options.kmin {
+        log::warn!(
+            "Setting kmin = {} instead of {}, so that kmax / 2 + 1 <= kmin < kmax (kmax = {})",
+            kmin,
+            options.kmin,
+            kmax
+        );
+    }
+    // within i32 range
+    enc_options.kmin = kmin as i32;
+    enc_options.kmax = kmax as i32;
     enc_options.allow_mixed = if options.allow_mixed { 1 } else { 0 };
     enc_options.verbose = if options.verbose { 1 } else { 0 };
 
     Ok(enc_options)
 }
 
+/// Write `metadata` chunks into assembled webp `data`. The mux API sets VP8X flags accordingly
+fn write_metadata(data: &WebPData, metadata: &Metadata) -> Result<WebPData, Error> {
+    let mux = MuxWrapper::new(data).ok_or(Error::MetadataWriteFailed)?;
+
+    for (fourcc, chunk) in metadata.chunks() {
+        mux.set_chunk(fourcc, chunk)
+            .map_err(|_| Error::MetadataWriteFailed)?;
+    }
+
+    let data = mux.assemble().map_err(|_| Error::MetadataWriteFailed)?;
+
+    log::trace!(
+        "Wrote metadata chunks, output binary size {} bytes",
+        data.len()
+    );
+
+    Ok(data)
+}
+
+/// Wrapper for libwebp `WebPMux`, referencing (not copying) the data it is given
+///
+/// Failing calls return the `WebPMuxError` of libwebp
+pub(crate) struct MuxWrapper<'a> {
+    mux: *mut webp::WebPMux,
+    _data: PhantomData<&'a [u8]>,
+}
+
+impl<'a> MuxWrapper<'a> {
+    /// Mux of the webp file `data`, `None` if it can not be parsed
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let data = webp::WebPData {
+            bytes: data.as_ptr(),
+            size: data.len(),
+        };
+
+        Self::from_ptr(unsafe { webp::WebPMuxCreate(&data, 0) })
+    }
+
+    /// Empty mux, `None` if the allocation fails
+    pub fn new_empty() -> Option<Self> {
+        Self::from_ptr(unsafe { webp::WebPMuxNew() })
+    }
+
+    fn from_ptr(mux: *mut webp::WebPMux) -> Option<Self> {
+        match mux.is_null() {
+            true => None,
+            false => Some(Self {
+                mux,
+                _data: PhantomData,
+            }),
+        }
+    }
+
+    pub fn set_chunk(&self, fourcc: &[u8; 4], chunk: &'a [u8]) -> Result<(), webp::WebPMuxError> {
+        let chunk = to_webp_data(chunk);
+        mux_result(unsafe {
+            webp::WebPMuxSetChunk(self.mux, fourcc.as_ptr() as *const _, &chunk, 0)
+        })
+    }
+
+    /// Append an animation frame, `frame.bitstream` is replaced with `bitstream`
+    pub fn push_frame(
+        &self,
+        bitstream: &'a [u8],
+        mut frame: webp::WebPMuxFrameInfo,
+    ) -> Result<(), webp::WebPMuxError> {
+        frame.bitstream = to_webp_data(bitstream);
+        mux_result(unsafe { webp::WebPMuxPushFrame(self.mux, &frame, 0) })
+    }
+
+    pub fn set_animation_params(
+        &self,
+        params: webp::WebPMuxAnimParams,
+    ) -> Result<(), webp::WebPMuxError> {
+        mux_result(unsafe { webp::WebPMuxSetAnimationParams(self.mux, &params) })
+    }
+
+    pub fn set_canvas_size(&self, (width, height): (u32, u32)) -> Result<(), webp::WebPMuxError> {
+        mux_result(unsafe { webp::WebPMuxSetCanvasSize(self.mux, width as i32, height as i32) })
+    }
+
+    pub fn assemble(&self) -> Result<WebPData, webp::WebPMuxError> {
+        let mut data = WebPData::new();
+        mux_result(unsafe { webp::WebPMuxAssemble(self.mux, data.inner_ref()) })?;
+        Ok(data)
+    }
+}
+
+fn to_webp_data(data: &[u8]) -> webp::WebPData {
+    webp::WebPData {
+        bytes: data.as_ptr(),
+        size: data.len(),
+    }
+}
+
+fn mux_result(error: webp::WebPMuxError) -> Result<(), webp::WebPMuxError> {
+    match error {
+        webp::WEBP_MUX_OK => Ok(()),
+        error => Err(error),
+    }
+}
+
+impl<'a> Drop for MuxWrapper<'a> {
+    fn drop(&mut self) {
+        unsafe { webp::WebPMuxDelete(self.mux) };
+    }
+}
+
 struct EncoderWrapper {
     encoder: *mut webp::WebPAnimEncoder,
 
@@ -293,6 +762,18 @@ impl EncoderWrapper {
 
         Ok(Self { encoder, options })
     }
+
+    /// Error message of the last failed libwebp call, empty if there is none
+    pub fn error_message(&self) -> String {
+        let message = unsafe { webp::WebPAnimEncoderGetError(self.encoder) };
+        if message.is_null() {
+            return String::new();
+        }
+
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
 }
 
 impl Drop for EncoderWrapper {
@@ -301,8 +782,65 @@ impl Drop for EncoderWrapper {
     }
 }
 
-struct PictureWrapper {
+/// A frame stored in a larger buffer, e.g. a buffer with row padding or a sub-rectangle of
+/// a bigger surface. Used with [`Encoder::add_frame_view`]
+///
+/// Pixels are in the [`ColorMode`] of the [`Encoder`]
+///
+/// ```
+/// # use webp_animation::prelude::*;
+/// #
+/// // encode the 2x2 pixel top right corner of a 4x4 Rgba surface
+/// let surface = [0u8; 4 * 4 * 4];
+/// let mut encoder = Encoder::new((2, 2)).unwrap();
+///
+/// encoder.add_frame_view(&FrameView {
+///     data: &surface,
+///     stride: 4 * 4,
+///     offset: (2, 0),
+///     dimensions: (2, 2),
+/// }, 0).unwrap();
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct FrameView<'a> {
+    /// Buffer containing the frame
+    pub data: &'a [u8],
+    /// Distance between the starts of two rows in `data`, in bytes
+    pub stride: usize,
+    /// Position (`x`, `y`) of the top-left pixel of the frame in `data`, in pixels
+    pub offset: (u32, u32),
+    /// Size of the frame (`width`, `height`), must match the encoder dimensions
+    pub dimensions: (u32, u32),
+}
+
+impl<'a> FrameView<'a> {
+    /// Pixels of the view, from the top-left pixel up to the end of the last row
+    pub(crate) fn pixels(&self, color_mode: ColorMode) -> Result<&'a [u8], Error> {
+        let (width, height) = self.dimensions;
+        let (x, y) = self.offset;
+        let pixel_size = color_mode.size();
+
+        // libwebp takes the stride as an int
+        let min_stride = (x as usize + width as usize) * pixel_size;
+        if self.stride < min_stride || self.stride > i32::MAX as usize {
+            return Err(Error::InvalidStride(self.stride, min_stride));
+        }
+
+        let start = y as usize * self.stride + x as usize * pixel_size;
+        let end = start + (height as usize - 1) * self.stride + width as usize * pixel_size;
+        if self.data.len() < end {
+            return Err(Error::BufferSizeFailed(end, self.data.len()));
+        }
+
+        Ok(&self.data[start..end])
+    }
+}
+
+pub(crate) struct PictureWrapper {
     picture: webp::WebPPicture,
+    /// Input converted into a format libwebp can import, for premultiplied and packed color
+    /// modes
+    converted: Vec<u8>,
 }
 
 impl PictureWrapper {
@@ -317,55 +855,192 @@ impl PictureWrapper {
         picture.height = dimensions.1 as i32;
         picture.use_argb = 1;
 
-        Ok(Self { picture })
+        Ok(Self {
+            picture,
+            converted: Vec::new(),
+        })
     }
 
     pub fn as_webp_picture_ref(&mut self) -> &mut webp::WebPPicture {
+        // libwebp only sets the error code on failure
+        self.picture.error_code = webp::VP8_ENC_OK;
         &mut self.picture
     }
 
-    pub fn set_data(&mut self, data: &[u8], color_mode: ColorMode) -> Result<(), Error> {
-        let received_len = data.len();
-        let expected_len = self.data_size(color_mode);
-        if received_len != expected_len {
-            return Err(Error::BufferSizeFailed(expected_len, received_len));
+    /// Call `progress_hook` with `user_data` while encoding, which must outlive the picture
+    fn set_progress_hook(&mut self, user_data: *mut std::os::raw::c_void) {
+        self.picture.progress_hook = Some(progress_hook);
+        self.picture.user_data = user_data;
+    }
+
+    /// Error reported by libwebp for the last failed import or encode of the picture
+    pub fn error(&self) -> EncodingError {
+        EncodingError::from_webp(self.picture.error_code)
+    }
+
+    /// Encode the picture on its own into a still webp file
+    pub fn encode(&mut self, config: &ConfigContainer) -> Result<Vec<u8>, EncodingError> {
+        let mut output = Vec::new();
+
+        let picture = self.as_webp_picture_ref();
+        picture.writer = Some(write_to_vec);
+        picture.custom_ptr = &mut output as *mut Vec<u8> as *mut _;
+
+        let result = unsafe { webp::WebPEncode(config.as_ptr(), picture) };
+
+        // `output` is only valid during this call
+        self.picture.writer = None;
+        self.picture.custom_ptr = ptr::null_mut();
+
+        match result {
+            0 => Err(self.error()),
+            _ => Ok(output),
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.picture.width as u32, self.picture.height as u32)
+    }
+
+    /// Copy of the imported pixels in Rgba
+    pub fn rgba(&self) -> Vec<u8> {
+        let (width, height) = self.dimensions();
+        let stride = self.picture.argb_stride as usize;
+
+        // pictures are imported as argb, see `new`
+        let argb = unsafe { slice::from_raw_parts(self.picture.argb, stride * height as usize) };
+
+        let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+        for row in argb.chunks(stride) {
+            for &pixel in &row[..width as usize] {
+                let [a, r, g, b] = pixel.to_be_bytes();
+                rgba.extend_from_slice(&[r, g, b, a]);
+            }
+        }
+        rgba
+    }
+
+    pub fn set_data(&mut self, view: &FrameView, color_mode: ColorMode) -> Result<(), Error> {
+        if view.dimensions != self.dimensions() {
+            return Err(Error::WrongFrameDimensions(
+                view.dimensions,
+                self.dimensions(),
+            ));
         }
 
+        let data = view.pixels(color_mode)?;
+        let row_size = self.picture.width as usize * color_mode.size();
+        let rows = data.chunks(view.stride).map(|row| &row[..row_size]);
+
+        let (data, stride, color_mode) = match color_mode {
+            ColorMode::RgbaPremultiplied | ColorMode::ArgbPremultiplied => {
+                self.converted.clear();
+                rows.for_each(|row| unpremultiply(row, color_mode, &mut self.converted));
+                (&self.converted[..], row_size, ColorMode::Rgba)
+            }
+            ColorMode::BgraPremultiplied => {
+                self.converted.clear();
+                rows.for_each(|row| unpremultiply(row, color_mode, &mut self.converted));
+                (&self.converted[..], row_size, ColorMode::Bgra)
+            }
+            ColorMode::Rgb565 | ColorMode::Rgba4444 => {
+                self.converted.clear();
+                rows.for_each(|row| unpack(row, color_mode, &mut self.converted));
+                (&self.converted[..], row_size * 2, ColorMode::Rgba)
+            }
+            _ => (data, view.stride, color_mode),
+        };
+
+        self.picture.error_code = webp::VP8_ENC_OK;
+
         if unsafe {
-            let size = color_mode.size() as i32;
+            // validated to fit by `FrameView::pixels`
+            let stride = stride as i32;
 
             match color_mode {
-                ColorMode::Rgba => webp::WebPPictureImportRGBA(
-                    &mut self.picture,
-                    data.as_ptr(),
-                    self.picture.width * size,
-                ),
-                ColorMode::Bgra => webp::WebPPictureImportBGRA(
-                    &mut self.picture,
-                    data.as_ptr(),
-                    self.picture.width * size,
-                ),
-                ColorMode::Rgb => webp::WebPPictureImportRGB(
-                    &mut self.picture,
-                    data.as_ptr(),
-                    self.picture.width * size,
-                ),
-                ColorMode::Bgr => webp::WebPPictureImportBGR(
-                    &mut self.picture,
-                    data.as_ptr(),
-                    self.picture.width * size,
-                ),
+                ColorMode::Rgba => {
+                    webp::WebPPictureImportRGBA(&mut self.picture, data.as_ptr(), stride)
+                }
+                ColorMode::Bgra => {
+                    webp::WebPPictureImportBGRA(&mut self.picture, data.as_ptr(), stride)
+                }
+                ColorMode::Rgb => {
+                    webp::WebPPictureImportRGB(&mut self.picture, data.as_ptr(), stride)
+                }
+                ColorMode::Bgr => {
+                    webp::WebPPictureImportBGR(&mut self.picture, data.as_ptr(), stride)
+                }
+                _ => unreachable!("{:?} has been converted to Rgba / Bgra above", color_mode),
             }
         } == 0
         {
-            return Err(Error::PictureImportFailed);
+            return Err(Error::PictureImportFailed(self.error()));
         }
 
         Ok(())
     }
+}
+
+/// libwebp writer appending to the `Vec<u8>` in `custom_ptr` of the picture
+extern "C" fn write_to_vec(
+    data: *const u8,
+    data_size: usize,
+    picture: *const webp::WebPPicture,
+) -> i32 {
+    if data_size > 0 {
+        unsafe {
+            let output = &mut *((*picture).custom_ptr as *mut Vec<u8>);
+            output.extend_from_slice(slice::from_raw_parts(data, data_size));
+        }
+    }
+    1
+}
+
+/// Convert premultiplied `data` in `color_mode` into straight alpha, appended to `output`.
+/// Argb is converted into Rgba, other modes keep their channel order
+fn unpremultiply(data: &[u8], color_mode: ColorMode, output: &mut Vec<u8>) {
+    let start = output.len();
+    output.extend_from_slice(data);
+
+    for pixel in output[start..].chunks_exact_mut(4) {
+        if color_mode == ColorMode::ArgbPremultiplied {
+            pixel.rotate_left(1);
+        }
+
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel[0..3] {
+            *channel = match alpha {
+                0 => 0,
+                _ => ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8,
+            };
+        }
+    }
+}
 
-    fn data_size(&self, color_mode: ColorMode) -> usize {
-        self.picture.width as usize * self.picture.height as usize * color_mode.size()
+/// Expand Rgb565 or Rgba4444 `data` into Rgba, appended to `output`. High bits of each
+/// channel are repeated in the low bits, so that full intensity maps to 255
+fn unpack(data: &[u8], color_mode: ColorMode, output: &mut Vec<u8>) {
+    for pixel in data.chunks_exact(2) {
+        let rgba = match color_mode {
+            ColorMode::Rgb565 => {
+                let r = pixel[0] >> 3;
+                let g = ((pixel[0] & 0x07) << 3) | (pixel[1] >> 5);
+                let b = pixel[1] & 0x1f;
+                [
+                    (r << 3) | (r >> 2),
+                    (g << 2) | (g >> 4),
+                    (b << 3) | (b >> 2),
+                    0xff,
+                ]
+            }
+            _ => [
+                (pixel[0] & 0xf0) | (pixel[0] >> 4),
+                (pixel[0] << 4) | (pixel[0] & 0x0f),
+                (pixel[1] & 0xf0) | (pixel[1] >> 4),
+                (pixel[1] << 4) | (pixel[1] & 0x0f),
+            ],
+        };
+        output.extend_from_slice(&rgba);
     }
 }
 
@@ -378,7 +1053,10 @@ impl Drop for PictureWrapper {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Decoder, EncodingType, Frame, LossyEncodingConfig};
+    use crate::{
+        AnimParams, Decoder, DecoderOptions, Demuxer, EncodingType, Frame, FrameStats, LoopCount,
+        LosslessEncodingConfig, LossyEncodingConfig,
+    };
     use std::fs::File;
     use std::io::prelude::*;
 
@@ -393,7 +1071,7 @@ mod tests {
             encoder.add_frame(frame.data(), frame.timestamp()).unwrap();
         }
         let webp_data = encoder.finalize(440).unwrap();
-        assert!(webp_data.len() > 0);
+        assert!(!webp_data.is_empty());
         assert_eq!(&webp_data[..5], &[82, 73, 70, 70, 18]);
 
         // decode previously encoded data
@@ -438,6 +1116,276 @@ mod tests {
         assert_eq!(frames[0].data(), &[0u8; 400 * 400 * 4]);
     }
 
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            icc_profile: Some(vec![1, 2, 3, 4, 5]),
+            exif: Some(b"II*\0\x08\0\0\0\0\0\0\0".to_vec()),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+        };
+
+        let mut encoder = Encoder::new_with_options(
+            (4, 4),
+            EncoderOptions {
+                metadata: metadata.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        encoder.add_frame(&[0u8; 4 * 4 * 4], 0).unwrap();
+        encoder.add_frame(&[255u8; 4 * 4 * 4], 100).unwrap();
+        let buf = encoder.finalize(200).unwrap();
+
+        let decoder = Decoder::new(&buf).unwrap();
+        let info = decoder.info();
+        assert!(info.has_icc_profile && info.has_exif && info.has_xmp);
+        assert_eq!(decoder.icc_profile(), metadata.icc_profile.as_deref());
+        assert_eq!(decoder.exif(), metadata.exif.as_deref());
+        assert_eq!(decoder.xmp(), metadata.xmp.as_deref());
+        assert_eq!(decoder.into_iter().count(), 2);
+
+        // only the set chunks are written
+        let mut encoder = Encoder::new_with_options(
+            (4, 4),
+            EncoderOptions {
+                metadata: Metadata {
+                    exif: metadata.exif.clone(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        encoder.add_frame(&[0u8; 4 * 4 * 4], 0).unwrap();
+        let buf = encoder.finalize(100).unwrap();
+
+        let decoder = Decoder::new(&buf).unwrap();
+        let info = decoder.info();
+        assert!(!info.has_icc_profile && info.has_exif && !info.has_xmp);
+        assert_eq!(decoder.icc_profile(), None);
+        assert_eq!(decoder.exif(), metadata.exif.as_deref());
+    }
+
+    #[test]
+    fn test_premultiplied_input() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let decode = |buffer: &[u8], color_mode| -> Vec<Frame> {
+            let options = DecoderOptions {
+                color_mode,
+                ..Default::default()
+            };
+            Decoder::new_with_options(buffer, options)
+                .unwrap()
+                .into_iter()
+                .collect()
+        };
+
+        for color_mode in [
+            ColorMode::RgbaPremultiplied,
+            ColorMode::BgraPremultiplied,
+            ColorMode::ArgbPremultiplied,
+        ] {
+            let frames = decode(&buffer, color_mode);
+
+            let mut encoder = Encoder::new_with_options(
+                (400, 400),
+                EncoderOptions {
+                    color_mode,
+                    encoding_config: Some(EncodingConfig {
+                        encoding_type: EncodingType::new_lossless(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            for frame in &frames {
+                encoder.add_frame(frame.data(), frame.timestamp()).unwrap();
+            }
+            let webp_data = encoder.finalize(440).unwrap();
+
+            // premultiplying the un-premultiplied values may be off by one
+            let decoded = decode(&webp_data, color_mode);
+            assert_eq!(decoded.len(), frames.len());
+            for (f1, f2) in decoded.iter().zip(frames.iter()) {
+                assert!(f1
+                    .data()
+                    .iter()
+                    .zip(f2.data())
+                    .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1));
+            }
+        }
+
+        let mut output = Vec::new();
+        unpremultiply(
+            &[0, 64, 32, 128, 9, 9, 9, 0],
+            ColorMode::ArgbPremultiplied,
+            &mut output,
+        );
+        assert_eq!(output, [0, 0, 0, 0, 255, 255, 0, 9]);
+        output.clear();
+        unpremultiply(&[64, 32, 0, 128], ColorMode::RgbaPremultiplied, &mut output);
+        assert_eq!(output, [128, 64, 0, 128]);
+    }
+
+    #[test]
+    fn test_packed_input() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let decode = |buffer: &[u8], color_mode| -> Vec<Frame> {
+            let options = DecoderOptions {
+                color_mode,
+                ..Default::default()
+            };
+            Decoder::new_with_options(buffer, options)
+                .unwrap()
+                .into_iter()
+                .collect()
+        };
+
+        for color_mode in [ColorMode::Rgb565, ColorMode::Rgba4444] {
+            let frames = decode(&buffer, color_mode);
+
+            let mut encoder = Encoder::new_with_options(
+                (400, 400),
+                EncoderOptions {
+                    color_mode,
+                    encoding_config: Some(EncodingConfig {
+                        encoding_type: EncodingType::new_lossless(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                encoder.add_frame(&[0u8; 400 * 400 * 4], 0).unwrap_err(),
+                Error::BufferSizeFailed(320_000, 640_000)
+            );
+            for frame in &frames {
+                encoder.add_frame(frame.data(), frame.timestamp()).unwrap();
+            }
+            let webp_data = encoder.finalize(440).unwrap();
+
+            // expanded channels pack back into the same bits
+            let decoded = decode(&webp_data, color_mode);
+            assert_eq!(decoded.len(), frames.len());
+            for (f1, f2) in decoded.iter().zip(frames.iter()) {
+                assert_eq!(f1.data(), f2.data());
+            }
+        }
+
+        let mut output = Vec::new();
+        unpack(&[0xf8, 0x1f, 0x07, 0xe0], ColorMode::Rgb565, &mut output);
+        assert_eq!(output, [255, 0, 255, 255, 0, 255, 0, 255]);
+        output.clear();
+        unpack(&[0xf0, 0x8f], ColorMode::Rgba4444, &mut output);
+        assert_eq!(output, [255, 0, 136, 255]);
+    }
+
+    #[test]
+    fn test_strided_input() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+
+        for color_mode in [ColorMode::Rgba, ColorMode::RgbaPremultiplied] {
+            let options = DecoderOptions {
+                color_mode,
+                ..Default::default()
+            };
+            let frames: Vec<_> = Decoder::new_with_options(&buffer, options)
+                .unwrap()
+                .into_iter()
+                .take(3)
+                .collect();
+
+            let new_encoder = || {
+                Encoder::new_with_options(
+                    (400, 400),
+                    EncoderOptions {
+                        color_mode,
+                        encoding_config: Some(EncodingConfig {
+                            encoding_type: EncodingType::new_lossless(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            };
+
+            let mut packed = new_encoder();
+            let mut strided = new_encoder();
+            let mut viewed = new_encoder();
+
+            // rows padded to 256 bytes, and the frame at (5, 3) of a 410x405 surface
+            let stride = (400 * 4 + 255) & !255;
+            let surface_stride = (410 * 4 + 255) & !255;
+
+            for frame in &frames {
+                let mut padded = vec![0x77u8; stride * 399 + 400 * 4];
+                let mut surface = vec![0x77u8; surface_stride * 405];
+                for (y, row) in frame.data().chunks_exact(400 * 4).enumerate() {
+                    padded[y * stride..][..400 * 4].copy_from_slice(row);
+                    surface[(y + 3) * surface_stride + 5 * 4..][..400 * 4].copy_from_slice(row);
+                }
+
+                let timestamp = frame.timestamp();
+                packed.add_frame(frame.data(), timestamp).unwrap();
+                strided
+                    .add_frame_strided(&padded, stride, timestamp)
+                    .unwrap();
+
+                let view = FrameView {
+                    data: &surface,
+                    stride: surface_stride,
+                    offset: (5, 3),
+                    dimensions: (400, 400),
+                };
+                viewed.add_frame_view(&view, timestamp).unwrap();
+            }
+
+            let expected = packed.finalize(200).unwrap();
+            assert!(*strided.finalize(200).unwrap() == *expected);
+            assert!(*viewed.finalize(200).unwrap() == *expected);
+        }
+
+        let mut encoder = Encoder::new((4, 2)).unwrap();
+        let data = [0u8; 64];
+        let view = |stride, offset, dimensions| FrameView {
+            data: &data,
+            stride,
+            offset,
+            dimensions,
+        };
+
+        assert_eq!(
+            encoder.add_frame_strided(&data, 15, 0).unwrap_err(),
+            Error::InvalidStride(15, 16)
+        );
+        assert_eq!(
+            encoder.add_frame_strided(&data[..47], 32, 0).unwrap_err(),
+            Error::BufferSizeFailed(48, 47)
+        );
+        assert_eq!(
+            encoder
+                .add_frame_view(&view(20, (2, 0), (4, 2)), 0)
+                .unwrap_err(),
+            Error::InvalidStride(20, 24)
+        );
+        assert_eq!(
+            encoder
+                .add_frame_view(&view(32, (4, 1), (4, 2)), 0)
+                .unwrap_err(),
+            Error::BufferSizeFailed(96, 64)
+        );
+        assert_eq!(
+            encoder
+                .add_frame_view(&view(32, (0, 0), (4, 4)), 0)
+                .unwrap_err(),
+            Error::WrongFrameDimensions((4, 4), (4, 2))
+        );
+        assert!(encoder.add_frame_view(&view(32, (4, 0), (4, 2)), 0).is_ok());
+    }
+
     #[test]
     fn test_failures() {
         let mut encoder = Encoder::new((400, 400)).unwrap();
@@ -471,6 +1419,14 @@ mod tests {
         );
     }
 
+    fn invalid_field(field: &'static str, value: &str, allowed: &str) -> Error {
+        Error::InvalidConfigField {
+            field,
+            value: value.to_string(),
+            allowed: allowed.to_string(),
+        }
+    }
+
     #[test]
     fn test_wrong_encoding_config() {
         let mut encoder = Encoder::new((4, 4)).unwrap();
@@ -496,7 +1452,38 @@ mod tests {
                     },
                 )
                 .unwrap_err(),
-            Error::InvalidEncodingConfig
+            invalid_field("quality", "101", "0..=100")
+        );
+
+        assert_eq!(
+            encoder
+                .add_frame_with_config(
+                    &[0u8; 4 * 4 * 4],
+                    5,
+                    &EncodingConfig {
+                        method: 7,
+                        ..Default::default()
+                    },
+                )
+                .unwrap_err(),
+            invalid_field("method", "7", "0..=6")
+        );
+
+        assert_eq!(
+            encoder
+                .add_frame_with_config(
+                    &[0u8; 4 * 4 * 4],
+                    5,
+                    &EncodingConfig {
+                        encoding_type: EncodingType::Lossless(LosslessEncodingConfig {
+                            near_lossless: 101,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )
+                .unwrap_err(),
+            invalid_field("near_lossless", "101", "0..=100")
         );
     }
 
@@ -508,7 +1495,7 @@ mod tests {
                 ..Default::default()
             })
             .unwrap_err(),
-            Error::InvalidEncodingConfig
+            invalid_field("segments", "9999", "1..=4")
         );
 
         assert_eq!(
@@ -517,7 +1504,7 @@ mod tests {
                 ..Default::default()
             })
             .unwrap_err(),
-            Error::InvalidEncodingConfig
+            invalid_field("pass", "11", "1..=10")
         );
 
         assert_eq!(
@@ -526,7 +1513,26 @@ mod tests {
                 ..Default::default()
             })
             .unwrap_err(),
-            Error::InvalidEncodingConfig
+            invalid_field("filter_sharpness", "8", "0..=7")
+        );
+
+        assert_eq!(
+            add_lossy_frame(LossyEncodingConfig {
+                qmin: 50,
+                qmax: 40,
+                ..Default::default()
+            })
+            .unwrap_err(),
+            invalid_field("qmax", "40", "50..=100")
+        );
+
+        assert_eq!(
+            add_lossy_frame(LossyEncodingConfig {
+                target_psnr: -1.,
+                ..Default::default()
+            })
+            .unwrap_err(),
+            invalid_field("target_psnr", "-1", ">= 0")
         );
 
         assert!(add_lossy_frame(LossyEncodingConfig {
@@ -536,6 +1542,462 @@ mod tests {
         .is_ok());
     }
 
+    #[test]
+    fn test_encoder_failure_reasons() {
+        // libwebp can not encode frames wider than 16383 pixels
+        let mut encoder = Encoder::new((20000, 1)).unwrap();
+        let error = encoder.add_frame(&[255u8; 20000 * 4], 0).unwrap_err();
+        assert_eq!(
+            error,
+            Error::EncoderAddFailed {
+                error: EncodingError::BadDimension,
+                message: "ERROR adding frame. WebPEncodingError: 5.".to_string(),
+            }
+        );
+        assert!(error.to_string().contains("VP8_ENC_ERROR_BAD_DIMENSION"));
+
+        // a transparent frame is encoded as a small sub-frame, but can not be muxed
+        let mut encoder = Encoder::new((20000, 1)).unwrap();
+        encoder.add_frame(&[0u8; 20000 * 4], 0).unwrap();
+        assert_eq!(
+            encoder.finalize(100).unwrap_err(),
+            Error::EncoderAssmebleFailed("ERROR assembling WebP: -2.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_finalize_with_report() {
+        let (width, height) = (64, 48);
+        let gradient: Vec<u8> = (0..width * height)
+            .flat_map(|i| vec![(i % width * 4) as u8, (i / width * 5) as u8, 128, 255])
+            .collect();
+        let mut sprite = gradient.clone();
+        for y in 10..20 {
+            for x in 30..40 {
+                let i = (y * width + x) * 4;
+                sprite[i..i + 4].copy_from_slice(&[0, 255, 0, 255]);
+            }
+        }
+
+        let encode = |measure_psnr| {
+            let mut encoder = Encoder::new_with_options(
+                (width as u32, height as u32),
+                EncoderOptions {
+                    measure_psnr,
+                    encoding_config: Some(EncodingConfig::new_lossy(90.)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            encoder.add_frame(&gradient, 100).unwrap();
+            encoder.add_frame(&sprite, 150).unwrap();
+            // identical to the previous frame, merged into it
+            encoder.add_frame(&sprite, 200).unwrap();
+            encoder
+                .add_frame_with_config(&gradient, 300, &EncodingConfig::default())
+                .unwrap();
+            encoder.finalize_with_report(400).unwrap()
+        };
+
+        let (webp_data, report) = encode(true);
+        assert_eq!(report.file_size, webp_data.len());
+        assert!(report.coded_size() < webp_data.len());
+
+        let frames = &report.frames;
+        let timestamps: Vec<_> = frames.iter().map(|f| (f.timestamp, f.duration)).collect();
+        assert_eq!(timestamps, [(100, 50), (150, 150), (300, 100)]);
+
+        assert!(frames[0].keyframe);
+        assert_eq!(frames[0].rect.width, 64);
+        assert!(!frames[0].lossless);
+
+        // only the changed area is encoded
+        assert!(!frames[1].keyframe);
+        assert!(frames[1].rect.width < 64 && frames[1].rect.height < 48);
+
+        assert!(frames[2].lossless);
+        assert_eq!(report.lossless_count(), 1);
+
+        let lossy = frames[0].psnr.unwrap();
+        assert!(lossy[0] > 30. && lossy[0] < 99.);
+        assert_eq!(lossy[3], 99.);
+        // psnr is measured on the whole canvas, the lossless sub-frame only improves it
+        assert!(frames[2].psnr.unwrap()[0] >= lossy[0]);
+        assert!(report.mean_psnr().unwrap()[1] > 30.);
+
+        // without measuring PSNR the rest of the report is the same
+        let (_, report_without_psnr) = encode(false);
+        assert_eq!(report_without_psnr.mean_psnr(), None);
+        for (with, without) in frames.iter().zip(report_without_psnr.frames.iter()) {
+            assert_eq!(
+                FrameStats {
+                    psnr: None,
+                    ..with.clone()
+                },
+                *without
+            );
+        }
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let (width, height) = (128, 96);
+        let frame = |seed: u32| -> Vec<u8> {
+            (0..width * height * 4)
+                .map(|i| (i * seed % 251) as u8)
+                .collect()
+        };
+        let new_encoder = || {
+            Encoder::new_with_options(
+                (width, height),
+                EncoderOptions {
+                    encoding_config: Some(EncodingConfig {
+                        method: 6,
+                        ..EncodingConfig::new_lossy(75.)
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        // every frame is reported, followed by the progress of libwebp
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut encoder = new_encoder();
+        let recorded = Rc::clone(&calls);
+        encoder.set_progress_callback(move |progress| {
+            recorded.borrow_mut().push(progress);
+            ControlFlow::Continue
+        });
+        encoder.add_frame(&frame(3), 0).unwrap();
+        encoder.add_frame(&frame(7), 100).unwrap();
+        encoder.finalize(200).unwrap();
+
+        let calls = calls.borrow();
+        for &frame_index in &[0, 1] {
+            let frame_calls: Vec<_> = calls
+                .iter()
+                .filter(|p| p.frame_index == frame_index)
+                .collect();
+            assert_eq!(frame_calls[0].percent, 0);
+            assert_eq!(frame_calls[0].timestamp, frame_index as i32 * 100);
+            assert!(frame_calls.len() > 2);
+            assert!(frame_calls.iter().all(|p| p.percent <= 100));
+            assert!(frame_calls.iter().any(|p| p.percent > 90));
+        }
+
+        let durations = |webp_data: &WebPData| -> Vec<i32> {
+            Demuxer::new(webp_data)
+                .unwrap()
+                .fragments()
+                .map(|fragment| fragment.duration())
+                .collect()
+        };
+
+        // cancel before libwebp starts encoding, the frame can be added again
+        let cancel = Rc::new(RefCell::new(true));
+        let mut encoder = new_encoder();
+        let should_cancel = Rc::clone(&cancel);
+        encoder.set_progress_callback(move |progress| {
+            match progress.frame_index == 1 && *should_cancel.borrow() {
+                true => ControlFlow::Break,
+                false => ControlFlow::Continue,
+            }
+        });
+        encoder.add_frame(&frame(3), 0).unwrap();
+        assert_eq!(
+            encoder.add_frame(&frame(7), 100).unwrap_err(),
+            Error::Cancelled
+        );
+        *cancel.borrow_mut() = false;
+        encoder.add_frame(&frame(7), 100).unwrap();
+        let webp_data = encoder.finalize(200).unwrap();
+        assert_eq!(durations(&webp_data), [100, 100]);
+
+        // cancel in the middle of encoding, libwebp has already extended the first frame
+        let mut encoder = new_encoder();
+        encoder.set_progress_callback(move |progress| {
+            match progress.frame_index == 1 && progress.percent > 0 {
+                true => ControlFlow::Break,
+                false => ControlFlow::Continue,
+            }
+        });
+        encoder.add_frame(&frame(3), 0).unwrap();
+        assert_eq!(
+            encoder.add_frame(&frame(7), 100).unwrap_err(),
+            Error::Cancelled
+        );
+        assert_eq!(
+            encoder.add_frame(&frame(7), 100).unwrap_err(),
+            Error::Cancelled
+        );
+        assert_eq!(encoder.finalize(200).unwrap_err(), Error::Cancelled);
+
+        // a panic of the callback is passed through libwebp
+        let mut encoder = new_encoder();
+        encoder.set_progress_callback(|progress| match progress.percent {
+            0 => ControlFlow::Continue,
+            _ => panic!("callback panic"),
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            encoder.add_frame(&frame(3), 0).unwrap();
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            encoder.add_frame(&frame(3), 0).unwrap_err(),
+            Error::Cancelled
+        );
+        drop(encoder);
+    }
+
+    #[test]
+    fn test_anim_params_round_trip() {
+        fn encode(anim_params: AnimParams) -> WebPData {
+            let options = EncoderOptions {
+                anim_params,
+                ..Default::default()
+            };
+            let mut encoder = Encoder::new_with_options((4, 4), options).unwrap();
+            encoder.add_frame(&[0u8; 4 * 4 * 4], 0).unwrap();
+            encoder.add_frame(&[255u8; 4 * 4 * 4], 50).unwrap();
+            encoder.finalize(100).unwrap()
+        }
+
+        fn anim_chunk(data: &[u8]) -> &[u8] {
+            let start = data.windows(4).position(|w| w == b"ANIM").unwrap();
+            let size = u32::from_le_bytes([
+                data[start + 4],
+                data[start + 5],
+                data[start + 6],
+                data[start + 7],
+            ]);
+            &data[start + 8..start + 8 + size as usize]
+        }
+
+        let anim_params = AnimParams {
+            loop_count: LoopCount::new(0x1234).unwrap(),
+            background_color: [0x11, 0x22, 0x33, 0x44],
+        };
+        let webp_data = encode(anim_params.clone());
+
+        // background color in [blue, green, red, alpha] byte order, then a 16-bit loop count
+        assert_eq!(
+            anim_chunk(&webp_data),
+            &[0x33, 0x22, 0x11, 0x44, 0x34, 0x12]
+        );
+
+        let info = Decoder::new(&webp_data).unwrap().info();
+        assert_eq!(info.loop_count, anim_params.loop_count);
+        assert_eq!(info.background_color, anim_params.background_color);
+
+        let reencoded = encode(AnimParams {
+            loop_count: info.loop_count,
+            background_color: info.background_color,
+        });
+        assert_eq!(anim_chunk(&reencoded), anim_chunk(&webp_data));
+
+        let webp_data = encode(Default::default());
+        assert_eq!(anim_chunk(&webp_data), &[0xff, 0xff, 0xff, 0xff, 0, 0]);
+    }
+
+    #[test]
+    fn test_wrong_encoder_options() {
+        let new_encoder = |kmin, kmax, loop_count| {
+            let loop_count = LoopCount::new(loop_count)?;
+            Encoder::new_with_options(
+                (4, 4),
+                EncoderOptions {
+                    kmin,
+                    kmax,
+                    anim_params: AnimParams {
+                        loop_count,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .map(|_| ())
+        };
+
+        assert_eq!(new_encoder(0, 0, 0), Ok(()));
+        assert_eq!(new_encoder(5, 1, 0), Ok(()));
+        assert_eq!(new_encoder(1, 2, 0), Ok(()));
+        assert_eq!(new_encoder(6, 10, 65535), Ok(()));
+        // libwebp adjusts kmin instead of refusing it
+        assert_eq!(new_encoder(5, 10, 0), Ok(()));
+        assert_eq!(new_encoder(10, 10, 0), Ok(()));
+        assert_eq!(
+            new_encoder(0, 0, 65536).unwrap_err(),
+            invalid_field("loop_count", "65536", "0..=65535")
+        );
+
+        let options = EncoderOptions {
+            encoding_config: Some(EncodingConfig {
+                quality: -1.,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            Encoder::new_with_options((4, 4), options).err().unwrap(),
+            invalid_field("quality", "-1", "0..=100")
+        );
+    }
+
+    /// Opaque frames with a moving square, an unchanged frame and a frame changed as a whole
+    fn moving_square_frames() -> Vec<(i32, Vec<u8>)> {
+        let frame = |square: Option<(usize, usize)>, shade: u8| {
+            let mut data = Vec::with_capacity(64 * 48 * 4);
+            for y in 0..48 {
+                for x in 0..64 {
+                    let inside = square.map_or(false, |(left, top)| {
+                        (left..left + 9).contains(&x) && (top..top + 9).contains(&y)
+                    });
+                    data.extend_from_slice(&match inside {
+                        true => [255, 0, 0, 255],
+                        false => [(x * 4) as u8, (y * 5) as u8, shade, 255],
+                    });
+                }
+            }
+            data
+        };
+
+        let mut corner = frame(None, 200);
+        corner[(47 * 64 + 63) * 4] = 0;
+
+        vec![
+            (0, frame(None, 0)),
+            (100, frame(Some((5, 7)), 0)),
+            (250, frame(Some((20, 31)), 0)),
+            (300, frame(Some((20, 31)), 0)),
+            (1000, frame(None, 200)),
+            (1100, corner),
+        ]
+    }
+
+    fn encode_frames(frames: &[(i32, Vec<u8>)], options: EncoderOptions) -> WebPData {
+        let mut encoder = Encoder::new_with_options((64, 48), options).unwrap();
+        for (timestamp, data) in frames {
+            encoder.add_frame(data, *timestamp).unwrap();
+        }
+        encoder.finalize(1500).unwrap()
+    }
+
+    #[test]
+    fn test_threaded_lossless() {
+        let frames = moving_square_frames();
+        let options = |threads, kmax| EncoderOptions {
+            kmax,
+            encoding_config: Some(EncodingConfig {
+                encoding_type: EncodingType::new_lossless(),
+                ..Default::default()
+            }),
+            threads,
+            ..Default::default()
+        };
+
+        for kmax in [0, 1, 2] {
+            let sequential = encode_frames(&frames, options(0, kmax));
+            let threaded = encode_frames(&frames, options(3, kmax));
+
+            let decoded: Vec<_> = Decoder::new(&threaded).unwrap().into_iter().collect();
+            let expected: Vec<_> = Decoder::new(&sequential).unwrap().into_iter().collect();
+            assert_eq!(decoded.len(), expected.len(), "kmax {}", kmax);
+            for (frame, expected) in decoded.iter().zip(&expected) {
+                assert_eq!(frame.timestamp(), expected.timestamp());
+                assert_eq!(frame.data(), expected.data());
+            }
+            assert_eq!(decoded.last().unwrap().data(), &frames[5].1[..]);
+
+            let full_frames = Demuxer::new(&threaded)
+                .unwrap()
+                .fragments()
+                .filter(|fragment| fragment.dimensions() == (64, 48))
+                .count();
+            let expected_full_frames = match kmax {
+                0 => 2, // first and changed as a whole
+                1 => decoded.len(),
+                _ => 3,
+            };
+            assert_eq!(full_frames, expected_full_frames);
+        }
+    }
+
+    #[test]
+    fn test_threaded_lossy() {
+        let frames = moving_square_frames();
+        let options = |threads| EncoderOptions {
+            encoding_config: Some(EncodingConfig::new_lossy(75.)),
+            threads,
+            ..Default::default()
+        };
+
+        // fragments are encoded independently, the worker count does not matter
+        let threaded = encode_frames(&frames, options(2));
+        assert_eq!(threaded[..], encode_frames(&frames, options(5))[..]);
+
+        let sequential = encode_frames(&frames, options(0));
+        let timestamps = |webp_data: &WebPData| {
+            Decoder::new(webp_data)
+                .unwrap()
+                .into_iter()
+                .map(|frame| frame.timestamp())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(&threaded), timestamps(&sequential));
+    }
+
+    #[test]
+    fn test_threaded_long_duration() {
+        let mut encoder = Encoder::new_with_options(
+            (4, 4),
+            EncoderOptions {
+                threads: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        encoder.add_frame(&[255u8; 4 * 4 * 4], 0).unwrap();
+        // libwebp refuses frames this long, unless they are merged from shorter ones
+        let webp_data = encoder.finalize(40_000_000).unwrap();
+
+        for frame in Decoder::new(&webp_data).unwrap().into_iter() {
+            assert_eq!(frame.data(), &[255u8; 4 * 4 * 4]);
+        }
+
+        let demuxer = Demuxer::new(&webp_data).unwrap();
+        let durations: Vec<_> = demuxer.fragments().map(|f| f.duration()).collect();
+        assert_eq!(
+            durations,
+            [0xff_ffff, 0xff_ffff, 40_000_000 - 2 * 0xff_ffff]
+        );
+    }
+
+    #[test]
+    fn test_threaded_unsupported_options() {
+        let options = EncoderOptions {
+            allow_mixed: true,
+            threads: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            Encoder::new_with_options((4, 4), options).err().unwrap(),
+            invalid_field("allow_mixed", "true", "false when threads is above 1")
+        );
+
+        let options = EncoderOptions {
+            minimize_size: true,
+            threads: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            Encoder::new_with_options((4, 4), options).err().unwrap(),
+            invalid_field("minimize_size", "true", "false when threads is above 1")
+        );
+    }
+
     fn add_lossy_frame(lossy_config: LossyEncodingConfig) -> Result<(), Error> {
         let mut encoder = Encoder::new((4, 4)).unwrap();
         encoder.add_frame_with_config(
@@ -1,4 +1,4 @@
-use std::mem;
+use std::{fmt::Display, mem, num::NonZeroU16, ops::RangeInclusive};
 
 use crate::{ColorMode, Error};
 
@@ -27,7 +27,11 @@ pub struct EncoderOptions {
     /// and `kmin >= kmax / 2 + 1`. Also, if `kmax <= 0`, then
     /// key-frame insertion is disabled; and if `kmax == 1`,
     /// then all frames will be key-frames (kmin value does
-    /// not matter for these special cases). Defaults to zero
+    /// not matter for these special cases). Like libwebp,
+    /// the encoder adjusts a `kmin` breaking these conditions
+    /// instead of refusing it, and logs a warning. See
+    /// [`EncoderOptions::keyframe_distances`] for the values
+    /// used. Defaults to zero
     pub kmin: isize,
     pub kmax: isize,
 
@@ -44,6 +48,98 @@ pub struct EncoderOptions {
     /// Default per-frame encoding config, optional. Can also be added per-frame
     /// by [`Encoder::add_frame_with_config`]
     pub encoding_config: Option<EncodingConfig>,
+
+    /// Metadata chunks (ICC profile, EXIF, XMP) to be written into the output. None by default
+    pub metadata: Metadata,
+
+    /// If true, keep a copy of every frame to measure [`FrameStats::psnr`] in
+    /// [`Encoder::finalize_with_report`]. Default `false`
+    ///
+    /// [`FrameStats::psnr`]: crate::FrameStats::psnr
+    pub measure_psnr: bool,
+
+    /// Number of worker threads encoding frames concurrently. `0` or `1` encodes every
+    /// frame on the calling thread with libwebp's animation encoder, which is the default
+    ///
+    /// With more threads, [`Encoder`] finds the area changed since the previous frame on
+    /// the calling thread, and queues it for the workers as a sub-frame, without blending.
+    /// Full frames are inserted at most every `kmax` frames. Sub-frames are selected
+    /// differently from libwebp, so the output is not byte-identical to the sequential
+    /// encoder. Lossless animations decode to the same pixels, lossy ones to similar pixels.
+    /// Encoding failures are returned by [`Encoder::finalize`]. `minimize_size` and
+    /// `allow_mixed` are not supported with threads
+    pub threads: usize,
+}
+
+impl EncoderOptions {
+    /// Check that all options are within the ranges accepted by libwebp
+    ///
+    /// Returns [`Error::InvalidConfigField`] naming the first offending field. Called by
+    /// [`Encoder::new_with_options`]
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let options = EncoderOptions {
+    ///     encoding_config: Some(EncodingConfig {
+    ///         quality: 101.,
+    ///         ..Default::default()
+    ///     }),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(
+    ///     options.validate().unwrap_err().to_string(),
+    ///     "InvalidConfigField: quality is 101, allowed values are 0..=100"
+    /// );
+    /// ```
+    ///
+    /// `kmin` and `kmax` are not checked, as libwebp adjusts them instead of refusing them.
+    /// Use [`EncoderOptions::keyframe_distances`] to find out whether they are adjusted
+    pub fn validate(&self) -> Result<(), Error> {
+        match &self.encoding_config {
+            Some(config) => config.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Minimum and maximum key-frame distances (`kmin`, `kmax`) as the encoder uses them
+    ///
+    /// libwebp adjusts `kmin` to satisfy the conditions documented at
+    /// [`EncoderOptions::kmin`], leaving the special cases of `kmax` and
+    /// [`EncoderOptions::minimize_size`] untouched
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let options = EncoderOptions {
+    ///     kmin: 9,
+    ///     kmax: 5,
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(options.keyframe_distances(), (4, 5));
+    /// ```
+    pub fn keyframe_distances(&self) -> (isize, isize) {
+        // libwebp stores the distances as ints
+        let clamp = |value: isize| value.clamp(i32::MIN as isize, i32::MAX as isize);
+        let (kmin, kmax) = (clamp(self.kmin), clamp(self.kmax));
+
+        // key-frame insertion is disabled, or every frame is a key-frame. libwebp ignores kmin
+        if kmax <= 1 || self.minimize_size {
+            return (kmin, kmax);
+        }
+
+        // same as SanitizeEncoderOptions of libwebp
+        let kmin_limit = kmax / 2 + 1;
+        let kmin = if kmin >= kmax {
+            kmax - 1
+        } else if kmin < kmin_limit && kmin_limit < kmax {
+            kmin_limit
+        } else {
+            kmin
+        };
+
+        (kmin, kmax)
+    }
 }
 
 impl Default for EncoderOptions {
@@ -57,15 +153,107 @@ impl Default for EncoderOptions {
             verbose: false,
             color_mode: ColorMode::Rgba,
             encoding_config: None,
+            metadata: Metadata::default(),
+            measure_psnr: false,
+            threads: 0,
         }
     }
 }
 
 /// Animation parameters
-#[derive(Clone, Default)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AnimParams {
-    /// Number of times to repeat the animation [0 = infinite, default].
-    pub loop_count: i32,
+    /// Number of times to play the animation. Default [`LoopCount::Infinite`]
+    pub loop_count: LoopCount,
+
+    /// Canvas background color in Rgba order. This is a hint for players only.
+    /// Default opaque white
+    pub background_color: [u8; 4],
+}
+
+impl Default for AnimParams {
+    fn default() -> Self {
+        Self {
+            loop_count: LoopCount::Infinite,
+            background_color: [255, 255, 255, 255],
+        }
+    }
+}
+
+impl AnimParams {
+    pub(crate) fn to_webp(&self) -> webp::WebPMuxAnimParams {
+        let [r, g, b, a] = self.background_color;
+        webp::WebPMuxAnimParams {
+            // libwebp stores the color as 0xAARRGGBB
+            bgcolor: u32::from_be_bytes([a, r, g, b]),
+            loop_count: self.loop_count.to_webp() as i32,
+        }
+    }
+}
+
+/// Number of times an animation is played, see [`AnimParams::loop_count`]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum LoopCount {
+    /// Loop forever
+    #[default]
+    Infinite,
+    /// Play the animation the given amount of times
+    Times(NonZeroU16),
+}
+
+impl LoopCount {
+    /// Loop count as stored in webp data, where `0` means infinite. The format
+    /// allows at most `65535` loops
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// assert_eq!(LoopCount::new(0).unwrap(), LoopCount::Infinite);
+    /// assert_eq!(LoopCount::new(3).unwrap().to_webp(), 3);
+    /// assert!(LoopCount::new(65536).is_err());
+    /// ```
+    pub fn new(loop_count: u32) -> Result<Self, Error> {
+        check_range("loop_count", loop_count, 0..=u16::MAX as u32)?;
+        Ok(match NonZeroU16::new(loop_count as u16) {
+            Some(count) => Self::Times(count),
+            None => Self::Infinite,
+        })
+    }
+
+    /// Loop count as stored in webp data, `0` for [`LoopCount::Infinite`]
+    pub fn to_webp(&self) -> u32 {
+        match self {
+            Self::Infinite => 0,
+            Self::Times(count) => count.get() as u32,
+        }
+    }
+}
+
+/// Metadata chunks to be written into the encoded webp data
+///
+/// Chunk payloads are written as is, without validation. See also
+/// [`Decoder::icc_profile`](crate::Decoder::icc_profile) and friends for reading them
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Metadata {
+    /// ICC color profile (`ICCP` chunk)
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF metadata (`EXIF` chunk)
+    pub exif: Option<Vec<u8>>,
+    /// XMP metadata (`XMP ` chunk)
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Chunks that have been set, as (`fourcc`, `payload`)
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = (&'static [u8; 4], &[u8])> {
+        vec![
+            (b"ICCP", &self.icc_profile),
+            (b"EXIF", &self.exif),
+            (b"XMP ", &self.xmp),
+        ]
+        .into_iter()
+        .filter_map(|(fourcc, chunk)| chunk.as_deref().map(|chunk| (fourcc, chunk)))
+    }
 }
 
 /// Encoding type
@@ -75,13 +263,91 @@ pub enum EncodingType {
     Lossy(LossyEncodingConfig),
 
     /// Losless encoding. Default.
-    Lossless,
+    Lossless(LosslessEncodingConfig),
 }
 
 impl EncodingType {
     pub fn new_lossy() -> Self {
         EncodingType::Lossy(LossyEncodingConfig::default())
     }
+
+    pub fn new_lossless() -> Self {
+        EncodingType::Lossless(LosslessEncodingConfig::default())
+    }
+}
+
+/// Hint for the type of the encoded image
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ImageHint {
+    /// No hint. Default
+    #[default]
+    Default,
+    /// Digital picture, like portrait, inner shot
+    Picture,
+    /// Outdoor photograph, with natural lighting
+    Photo,
+    /// Discrete tone image (graph, map-tile etc)
+    Graph,
+}
+
+impl ImageHint {
+    fn from_webp(image_hint: webp::WebPImageHint) -> Self {
+        match image_hint {
+            webp::WEBP_HINT_PICTURE => ImageHint::Picture,
+            webp::WEBP_HINT_PHOTO => ImageHint::Photo,
+            webp::WEBP_HINT_GRAPH => ImageHint::Graph,
+            _ => ImageHint::Default,
+        }
+    }
+
+    fn to_webp(self) -> webp::WebPImageHint {
+        match self {
+            ImageHint::Default => webp::WEBP_HINT_DEFAULT,
+            ImageHint::Picture => webp::WEBP_HINT_PICTURE,
+            ImageHint::Photo => webp::WEBP_HINT_PHOTO,
+            ImageHint::Graph => webp::WEBP_HINT_GRAPH,
+        }
+    }
+}
+
+/// Predefined lossy encoding parameters of libwebp, for typical types of source images.
+/// See [`EncodingConfig::from_preset`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// Default preset
+    Default,
+    /// Digital picture, like portrait, inner shot
+    Picture,
+    /// Outdoor photograph, with natural lighting
+    Photo,
+    /// Hand or line drawing, with high-contrast details
+    Drawing,
+    /// Small-sized colorful images
+    Icon,
+    /// Text-like
+    Text,
+}
+
+impl Preset {
+    fn to_webp(self) -> webp::WebPPreset {
+        match self {
+            Preset::Default => webp::WEBP_PRESET_DEFAULT,
+            Preset::Picture => webp::WEBP_PRESET_PICTURE,
+            Preset::Photo => webp::WEBP_PRESET_PHOTO,
+            Preset::Drawing => webp::WEBP_PRESET_DRAWING,
+            Preset::Icon => webp::WEBP_PRESET_ICON,
+            Preset::Text => webp::WEBP_PRESET_TEXT,
+        }
+    }
+
+    /// libwebp config initialized with the preset
+    fn webp_config(self, quality: f32) -> webp::WebPConfig {
+        unsafe {
+            let mut config = mem::zeroed();
+            assert!(webp::WebPConfigPreset(&mut config, self.to_webp(), quality) != 0);
+            config
+        }
+    }
 }
 
 /// Encoding configuration. Can be set for [`Encoder`] globally or per frame
@@ -102,7 +368,19 @@ pub struct EncodingConfig {
 
     /// Quality/speed trade-off (0=fast, 6=slower-better)
     pub method: usize,
-    // image_hint todo?
+
+    /// Hint for the image type
+    pub image_hint: ImageHint,
+
+    /// If true, try and use multi-threaded encoding (`thread_level`)
+    pub use_threads: bool,
+
+    /// If true, reduce memory usage (but increase CPU use)
+    pub low_memory: bool,
+
+    /// If true, preserve the exact RGB values under transparent area. Otherwise, discard
+    /// this invisible RGB information for better compression. The default is false
+    pub exact: bool,
 }
 
 impl EncodingConfig {
@@ -114,19 +392,104 @@ impl EncodingConfig {
         }
     }
 
+    /// Lossy encoding configuration from a libwebp [`Preset`], with `quality` between 0
+    /// and 100
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let config = EncodingConfig::from_preset(Preset::Photo, 80.);
+    /// assert_eq!(config.quality, 80.);
+    /// ```
+    pub fn from_preset(preset: Preset, quality: f32) -> Self {
+        Self::from_webp_config(&preset.webp_config(quality))
+    }
+
+    /// Lossless encoding configuration for compression `level` between 0 (fastest, lowest
+    /// compression) and 9 (slowest, best compression). Sets `quality` and `method` as
+    /// libwebp `WebPConfigLosslessPreset` does. `6` is a good default
+    ///
+    /// Returns [`Error::InvalidConfigField`] if `level` is out of range
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let config = EncodingConfig::lossless_level(9).unwrap();
+    /// assert_eq!((config.quality, config.method), (100., 6));
+    /// ```
+    pub fn lossless_level(level: u8) -> Result<Self, Error> {
+        let mut config = Preset::Default.webp_config(75.);
+        check_range("level", level, 0..=9)?;
+        if unsafe { webp::WebPConfigLosslessPreset(&mut config, level as i32) } == 0 {
+            return Err(Error::InvalidEncodingConfig);
+        }
+
+        Ok(Self::from_webp_config(&config))
+    }
+
+    fn from_webp_config(config: &webp::WebPConfig) -> Self {
+        Self {
+            encoding_type: match config.lossless {
+                0 => EncodingType::Lossy(LossyEncodingConfig::from_webp_config(config)),
+                _ => EncodingType::Lossless(LosslessEncodingConfig::from_webp_config(config)),
+            },
+            quality: config.quality,
+            method: config.method as usize,
+            image_hint: ImageHint::from_webp(config.image_hint),
+            use_threads: config.thread_level != 0,
+            low_memory: config.low_memory != 0,
+            exact: config.exact != 0,
+        }
+    }
+
+    /// Check that all fields are within the ranges accepted by libwebp
+    ///
+    /// Returns [`Error::InvalidConfigField`] naming the first offending field. Called when
+    /// the config is passed to [`Encoder`]
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let config = EncodingConfig {
+    ///     method: 7,
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(
+    ///     config.validate().unwrap_err().to_string(),
+    ///     "InvalidConfigField: method is 7, allowed values are 0..=6"
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        check_range("quality", self.quality, 0.0..=100.0)?;
+        check_range("method", self.method, 0..=6)?;
+
+        match &self.encoding_type {
+            EncodingType::Lossy(config) => config.validate(),
+            EncodingType::Lossless(config) => config.validate(),
+        }
+    }
+
     pub(crate) fn to_config_container(&self) -> Result<ConfigContainer, Error> {
         ConfigContainer::new(self)
     }
 
     pub(crate) fn apply_to(&self, webp_config: &mut webp::WebPConfig) {
         webp_config.lossless = match &self.encoding_type {
-            EncodingType::Lossy(lossless_config) => {
-                lossless_config.apply_to(webp_config);
+            EncodingType::Lossy(lossy_config) => {
+                lossy_config.apply_to(webp_config);
                 0
             }
-            EncodingType::Lossless => 1,
+            EncodingType::Lossless(lossless_config) => {
+                lossless_config.apply_to(webp_config);
+                1
+            }
         };
         webp_config.quality = self.quality;
+        webp_config.method = self.method as i32;
+        webp_config.image_hint = self.image_hint.to_webp();
+        webp_config.thread_level = self.use_threads as i32;
+        webp_config.low_memory = self.low_memory as i32;
+        webp_config.exact = self.exact as i32;
     }
 }
 
@@ -134,9 +497,13 @@ impl Default for EncodingConfig {
     fn default() -> Self {
         // src/enc/config_enc.c has defaults
         Self {
-            encoding_type: EncodingType::Lossless,
+            encoding_type: EncodingType::new_lossless(),
             quality: 1.,
             method: 4,
+            image_hint: ImageHint::Default,
+            use_threads: false,
+            low_memory: false,
+            exact: false,
         }
     }
 }
@@ -190,8 +557,8 @@ pub struct LossyEncodingConfig {
     /// In-loop filtering is not applied.
     pub show_compressed: bool,
 
-    /// preprocessing filter (0=none, 1=segment-smooth)
-    pub preprocessing: bool,
+    /// preprocessing filter (0=none, 1=segment-smooth, 2=pseudo-random dithering)
+    pub preprocessing: usize,
 
     /// log2(number of token partitions) in [0..3]
     /// Default is set to 0 for easier progressive decoding.
@@ -204,6 +571,16 @@ pub struct LossyEncodingConfig {
 
     /// if needed, use sharp (and slow) RGB->YUV conversion
     pub use_sharp_yuv: bool,
+
+    /// If true, auto-adjust the compression parameters to mimic the size of a JPEG
+    /// compressed image of the same quality
+    pub emulate_jpeg_size: bool,
+
+    /// minimum permissible quality factor, in [0..100]
+    pub qmin: usize,
+
+    /// maximum permissible quality factor, in [0..100]
+    pub qmax: usize,
 }
 
 impl Default for LossyEncodingConfig {
@@ -224,68 +601,100 @@ impl Default for LossyEncodingConfig {
             alpha_compression: true,
             alpha_filtering: 1,
             alpha_quality: 100,
-            preprocessing: false,
+            preprocessing: 0,
             partition_limit: 0,
             use_sharp_yuv: false,
+            emulate_jpeg_size: false,
+            qmin: 0,
+            qmax: 100,
         }
     }
 }
 
 impl LossyEncodingConfig {
     pub fn new_from_default_preset() -> Self {
-        Self {
-            ..Default::default()
-        }
+        Self::from_preset(Preset::Default)
     }
 
     pub fn new_from_picture_preset() -> Self {
-        Self {
-            sns_strength: 80,
-            filter_sharpness: 4,
-            filter_strength: 35,
-            preprocessing: false,
-            ..Default::default()
-        }
+        Self::from_preset(Preset::Picture)
     }
 
     pub fn new_from_photo_preset() -> Self {
-        Self {
-            sns_strength: 80,
-            filter_sharpness: 3,
-            filter_strength: 30,
-            preprocessing: false,
-            ..Default::default()
-        }
+        Self::from_preset(Preset::Photo)
     }
 
     pub fn new_from_drawing_preset() -> Self {
-        Self {
-            sns_strength: 25,
-            filter_sharpness: 6,
-            filter_strength: 10,
-            ..Default::default()
-        }
+        Self::from_preset(Preset::Drawing)
     }
 
     pub fn new_from_icon_preset() -> Self {
-        Self {
-            sns_strength: 0,
-            filter_strength: 0,
-            preprocessing: false,
-            ..Default::default()
-        }
+        Self::from_preset(Preset::Icon)
     }
 
     pub fn new_from_text_preset() -> Self {
+        Self::from_preset(Preset::Text)
+    }
+
+    /// Lossy parameters of a libwebp [`Preset`]
+    pub fn from_preset(preset: Preset) -> Self {
+        // quality is not part of the lossy parameters
+        Self::from_webp_config(&preset.webp_config(75.))
+    }
+
+    fn from_webp_config(config: &webp::WebPConfig) -> Self {
         Self {
-            sns_strength: 0,
-            filter_strength: 0,
-            preprocessing: false,
-            segments: 2,
-            ..Default::default()
+            target_size: config.target_size as usize,
+            target_psnr: config.target_PSNR,
+            segments: config.segments as usize,
+            sns_strength: config.sns_strength as usize,
+            filter_strength: config.filter_strength as usize,
+            filter_sharpness: config.filter_sharpness as usize,
+            filter_type: config.filter_type as usize,
+            autofilter: config.autofilter != 0,
+            alpha_compression: config.alpha_compression != 0,
+            alpha_filtering: config.alpha_filtering as usize,
+            alpha_quality: config.alpha_quality as usize,
+            pass: config.pass as usize,
+            show_compressed: config.show_compressed != 0,
+            preprocessing: config.preprocessing as usize,
+            partitions: config.partitions as usize,
+            partition_limit: config.partition_limit as isize,
+            use_sharp_yuv: config.use_sharp_yuv != 0,
+            emulate_jpeg_size: config.emulate_jpeg_size != 0,
+            qmin: config.qmin as usize,
+            qmax: config.qmax as usize,
         }
     }
 
+    fn validate(&self) -> Result<(), Error> {
+        if self.target_psnr.is_nan() || self.target_psnr < 0. {
+            return Err(Error::InvalidConfigField {
+                field: "target_psnr",
+                value: self.target_psnr.to_string(),
+                allowed: ">= 0".to_string(),
+            });
+        }
+
+        check_range("segments", self.segments, 1..=4)?;
+        check_range("sns_strength", self.sns_strength, 0..=100)?;
+        check_range("filter_strength", self.filter_strength, 0..=100)?;
+        check_range("filter_sharpness", self.filter_sharpness, 0..=7)?;
+        check_range("filter_type", self.filter_type, 0..=1)?;
+        // libwebp only checks the lower bound, larger values are accepted silently
+        check_range("alpha_filtering", self.alpha_filtering, 0..=2)?;
+        check_range("alpha_quality", self.alpha_quality, 0..=100)?;
+        check_range("pass", self.pass, 1..=10)?;
+        check_range("preprocessing", self.preprocessing, 0..=7)?;
+        check_range("partitions", self.partitions, 0..=3)?;
+        check_range("partition_limit", self.partition_limit, 0..=100)?;
+        check_range("qmin", self.qmin, 0..=100)?;
+        check_range("qmax", self.qmax, self.qmin.min(100)..=100)?;
+
+        // libwebp stores the target size as an int
+        check_range("target_size", self.target_size, 0..=i32::MAX as usize)
+    }
+
     fn apply_to(&self, webp_config: &mut webp::WebPConfig) {
         webp_config.target_size = self.target_size as i32;
         webp_config.target_PSNR = self.target_psnr;
@@ -304,7 +713,65 @@ impl LossyEncodingConfig {
         webp_config.partitions = self.partitions as i32;
         webp_config.partition_limit = self.partition_limit as i32;
         webp_config.use_sharp_yuv = self.use_sharp_yuv as i32;
+        webp_config.emulate_jpeg_size = self.emulate_jpeg_size as i32;
+        webp_config.qmin = self.qmin as i32;
+        webp_config.qmax = self.qmax as i32;
+    }
+}
+
+/// Parameters related to lossless compression only
+#[derive(Debug, Clone)]
+pub struct LosslessEncodingConfig {
+    /// Near lossless encoding [0 = max loss .. 100 = off (default)]
+    pub near_lossless: usize,
+
+    /// reserved for future lossless feature
+    pub use_delta_palette: bool,
+}
+
+impl Default for LosslessEncodingConfig {
+    fn default() -> Self {
+        Self {
+            // src/enc/config_enc.c contains defaults
+            near_lossless: 100,
+            use_delta_palette: false,
+        }
+    }
+}
+
+impl LosslessEncodingConfig {
+    fn validate(&self) -> Result<(), Error> {
+        check_range("near_lossless", self.near_lossless, 0..=100)
     }
+
+    fn from_webp_config(config: &webp::WebPConfig) -> Self {
+        Self {
+            near_lossless: config.near_lossless as usize,
+            use_delta_palette: config.use_delta_palette != 0,
+        }
+    }
+
+    fn apply_to(&self, webp_config: &mut webp::WebPConfig) {
+        webp_config.near_lossless = self.near_lossless as i32;
+        webp_config.use_delta_palette = self.use_delta_palette as i32;
+    }
+}
+
+/// Returns [`Error::InvalidConfigField`] if `value` of `field` is not within `allowed`
+pub(crate) fn check_range<T: PartialOrd + Display>(
+    field: &'static str,
+    value: T,
+    allowed: RangeInclusive<T>,
+) -> Result<(), Error> {
+    if allowed.contains(&value) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidConfigField {
+        field,
+        value: value.to_string(),
+        allowed: format!("{}..={}", allowed.start(), allowed.end()),
+    })
 }
 
 pub(crate) struct ConfigContainer {
@@ -319,8 +786,10 @@ impl ConfigContainer {
             config
         };
 
+        config.validate()?;
         config.apply_to(&mut webp_config);
 
+        // should not fail after validation above, unless libwebp adds restrictions
         if unsafe { webp::WebPValidateConfig(&webp_config) } == 0 {
             return Err(Error::InvalidEncodingConfig);
         }
@@ -410,4 +879,298 @@ mod tests {
         assert_eq!(left.qmin, def.qmin, "c.qmin");
         assert_eq!(left.qmax, def.qmax, "c.qmax");
     }
+
+    #[test]
+    fn test_config_lossy_fields() {
+        let config = ConfigContainer::new(&EncodingConfig {
+            encoding_type: EncodingType::Lossy(LossyEncodingConfig {
+                target_size: 1000,
+                target_psnr: 42.,
+                segments: 3,
+                sns_strength: 11,
+                filter_strength: 12,
+                filter_sharpness: 5,
+                filter_type: 0,
+                autofilter: true,
+                alpha_compression: false,
+                alpha_filtering: 2,
+                alpha_quality: 13,
+                pass: 7,
+                show_compressed: true,
+                preprocessing: 2,
+                partitions: 2,
+                partition_limit: 14,
+                use_sharp_yuv: true,
+                emulate_jpeg_size: true,
+                qmin: 15,
+                qmax: 85,
+            }),
+            quality: 70.,
+            method: 2,
+            image_hint: ImageHint::Photo,
+            use_threads: true,
+            low_memory: true,
+            exact: true,
+        })
+        .unwrap();
+
+        let c = config.as_ptr();
+        assert_eq!(c.lossless, 0);
+        assert_eq!(c.quality, 70.);
+        assert_eq!(c.method, 2);
+        assert_eq!(c.image_hint, webp::WEBP_HINT_PHOTO);
+        assert_eq!(c.thread_level, 1);
+        assert_eq!(c.low_memory, 1);
+        assert_eq!(c.exact, 1);
+
+        assert_eq!(c.target_size, 1000);
+        assert_eq!(c.target_PSNR, 42.);
+        assert_eq!(c.segments, 3);
+        assert_eq!(c.sns_strength, 11);
+        assert_eq!(c.filter_strength, 12);
+        assert_eq!(c.filter_sharpness, 5);
+        assert_eq!(c.filter_type, 0);
+        assert_eq!(c.autofilter, 1);
+        assert_eq!(c.alpha_compression, 0);
+        assert_eq!(c.alpha_filtering, 2);
+        assert_eq!(c.alpha_quality, 13);
+        assert_eq!(c.pass, 7);
+        assert_eq!(c.show_compressed, 1);
+        assert_eq!(c.preprocessing, 2);
+        assert_eq!(c.partitions, 2);
+        assert_eq!(c.partition_limit, 14);
+        assert_eq!(c.use_sharp_yuv, 1);
+        assert_eq!(c.emulate_jpeg_size, 1);
+        assert_eq!(c.qmin, 15);
+        assert_eq!(c.qmax, 85);
+    }
+
+    #[test]
+    fn test_config_lossless_fields() {
+        let config = ConfigContainer::new(&EncodingConfig {
+            encoding_type: EncodingType::Lossless(LosslessEncodingConfig {
+                near_lossless: 60,
+                use_delta_palette: true,
+            }),
+            quality: 30.,
+            method: 6,
+            image_hint: ImageHint::Graph,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let c = config.as_ptr();
+        assert_eq!(c.lossless, 1);
+        assert_eq!(c.quality, 30.);
+        assert_eq!(c.method, 6);
+        assert_eq!(c.image_hint, webp::WEBP_HINT_GRAPH);
+        assert_eq!(c.near_lossless, 60);
+        assert_eq!(c.use_delta_palette, 1);
+
+        for (hint, expected) in [
+            (ImageHint::Default, webp::WEBP_HINT_DEFAULT),
+            (ImageHint::Picture, webp::WEBP_HINT_PICTURE),
+        ] {
+            let config = ConfigContainer::new(&EncodingConfig {
+                image_hint: hint,
+                ..Default::default()
+            })
+            .unwrap();
+            assert_eq!(config.as_ptr().image_hint, expected);
+        }
+    }
+
+    fn config_bytes(config: &webp::WebPConfig) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                config as *const _ as *const u8,
+                mem::size_of::<webp::WebPConfig>(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_config_presets() {
+        for preset in [
+            Preset::Default,
+            Preset::Picture,
+            Preset::Photo,
+            Preset::Drawing,
+            Preset::Icon,
+            Preset::Text,
+        ] {
+            let expected = preset.webp_config(42.);
+            let config = ConfigContainer::new(&EncodingConfig::from_preset(preset, 42.)).unwrap();
+            assert_eq!(
+                config_bytes(config.as_ptr()),
+                config_bytes(&expected),
+                "{:?}",
+                preset
+            );
+        }
+
+        let photo = LossyEncodingConfig::new_from_photo_preset();
+        assert_eq!(photo.sns_strength, 80);
+        assert_eq!(photo.preprocessing, 2);
+        assert_eq!(LossyEncodingConfig::new_from_text_preset().segments, 2);
+    }
+
+    #[test]
+    fn test_config_lossless_levels() {
+        for level in 0..=9 {
+            let mut expected = Preset::Default.webp_config(75.);
+            assert!(unsafe { webp::WebPConfigLosslessPreset(&mut expected, level as i32) } != 0);
+
+            let config = EncodingConfig::lossless_level(level).unwrap();
+            assert!(matches!(config.encoding_type, EncodingType::Lossless(_)));
+
+            let config = ConfigContainer::new(&config).unwrap();
+            assert_eq!(config_bytes(config.as_ptr()), config_bytes(&expected));
+        }
+
+        assert_eq!(
+            EncodingConfig::lossless_level(10).unwrap_err(),
+            Error::InvalidConfigField {
+                field: "level",
+                value: "10".to_string(),
+                allowed: "0..=9".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_matches_libwebp() {
+        let lossy = |config: LossyEncodingConfig| EncodingConfig {
+            encoding_type: EncodingType::Lossy(config),
+            ..Default::default()
+        };
+        let lossless = |near_lossless| EncodingConfig {
+            encoding_type: EncodingType::Lossless(LosslessEncodingConfig {
+                near_lossless,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let default = LossyEncodingConfig::default;
+
+        let mut configs = Vec::new();
+        for value in [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 100, 101] {
+            configs.push(EncodingConfig {
+                quality: value as f32,
+                method: value,
+                ..Default::default()
+            });
+            configs.push(lossless(value));
+            configs.push(lossy(LossyEncodingConfig {
+                segments: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                sns_strength: value,
+                filter_strength: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                filter_sharpness: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                filter_type: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                alpha_quality: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                pass: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                preprocessing: value,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                partitions: value,
+                partition_limit: value as isize,
+                ..default()
+            }));
+            configs.push(lossy(LossyEncodingConfig {
+                qmin: value,
+                qmax: 10,
+                ..default()
+            }));
+        }
+        configs.push(lossy(LossyEncodingConfig {
+            target_psnr: -0.5,
+            ..default()
+        }));
+        configs.push(lossy(LossyEncodingConfig {
+            partition_limit: -1,
+            ..default()
+        }));
+
+        for config in configs {
+            let mut webp_config = Preset::Default.webp_config(75.);
+            config.apply_to(&mut webp_config);
+            let valid = unsafe { webp::WebPValidateConfig(&webp_config) } != 0;
+
+            assert_eq!(config.validate().is_ok(), valid, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_alpha_filtering_range() {
+        let config = |alpha_filtering| EncodingConfig {
+            encoding_type: EncodingType::Lossy(LossyEncodingConfig {
+                alpha_filtering,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        for alpha_filtering in 0..=2 {
+            assert_eq!(config(alpha_filtering).validate(), Ok(()));
+        }
+        // accepted by WebPValidateConfig
+        assert_eq!(
+            config(3).validate().unwrap_err(),
+            Error::InvalidConfigField {
+                field: "alpha_filtering",
+                value: "3".to_string(),
+                allowed: "0..=2".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_keyframe_distances() {
+        let distances = |kmin, kmax, minimize_size| {
+            EncoderOptions {
+                kmin,
+                kmax,
+                minimize_size,
+                ..Default::default()
+            }
+            .keyframe_distances()
+        };
+
+        assert_eq!(distances(6, 10, false), (6, 10));
+        assert_eq!(distances(9, 10, false), (9, 10));
+        assert_eq!(distances(5, 10, false), (6, 10));
+        assert_eq!(distances(-3, 10, false), (6, 10));
+        assert_eq!(distances(10, 10, false), (9, 10));
+        assert_eq!(distances(9, 5, false), (4, 5));
+        assert_eq!(distances(0, 2, false), (0, 2));
+        assert_eq!(distances(7, 2, false), (1, 2));
+
+        // special cases are left to libwebp
+        assert_eq!(distances(5, 1, false), (5, 1));
+        assert_eq!(distances(5, 0, false), (5, 0));
+        assert_eq!(distances(5, 10, true), (5, 10));
+        assert_eq!(
+            distances(3, isize::MAX, false),
+            (i32::MAX as isize / 2 + 1, i32::MAX as isize)
+        );
+    }
 }
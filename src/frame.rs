@@ -87,7 +87,12 @@ impl Frame {
         self.timestamp
     }
 
-    /// Get decoded frame data, size `width` * `height` * 4, pixels in [`ColorMode`] format
+    /// Get decoded frame data, size `width` * `height` * [`ColorMode::size`], pixels in
+    /// [`ColorMode`] format
+    ///
+    /// Pixels are stored row by row, each pixel in the channel order of the color mode. The
+    /// packed [`ColorMode::Rgb565`] and [`ColorMode::Rgba4444`] modes take two bytes per
+    /// pixel, see their documentation for the bit layout
     pub fn data(&self) -> &[u8] {
         &self.frame_data
     }
@@ -137,3 +142,89 @@ impl Debug for Frame {
         )
     }
 }
+
+/// A reference to a frame decoded by [`Decoder::next_frame_ref`], borrowing the decoder canvas
+///
+/// Valid until the next frame is decoded. Accessors behave as in [`Frame`]
+///
+/// ```rust
+/// # use webp_animation::{Decoder, ColorMode};
+/// #
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let mut decoder = Decoder::new(&buffer).unwrap();
+///
+/// let frame = decoder.next_frame_ref().unwrap();
+/// assert_eq!(frame.timestamp(), 40);
+/// assert_eq!(frame.data()[0..4], [0, 0, 0, 255]);
+///
+/// let owned = frame.to_frame();
+/// ```
+#[derive(Copy, Clone)]
+pub struct FrameRef<'a> {
+    timestamp: i32,
+    frame_data: &'a [u8],
+    color_mode: ColorMode,
+    dimensions: (u32, u32),
+}
+
+impl<'a> FrameRef<'a> {
+    pub(crate) fn new_from_decoder(
+        timestamp: i32,
+        color_mode: ColorMode,
+        frame_data: &'a [u8],
+        dimensions: (u32, u32),
+    ) -> Self {
+        Self {
+            timestamp,
+            frame_data,
+            color_mode,
+            dimensions,
+        }
+    }
+
+    /// Get dimensions of the frame (`width`, `height`)
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// Get [`ColorMode`] of the frame (consistent accross frames)
+    pub fn color_mode(&self) -> ColorMode {
+        self.color_mode
+    }
+
+    /// Get timestamp of the frame in milliseconds
+    pub fn timestamp(&self) -> i32 {
+        self.timestamp
+    }
+
+    /// Get decoded frame data, size `width` * `height` * [`ColorMode::size`], pixels in
+    /// [`ColorMode`] format
+    ///
+    /// Pixels are stored row by row, each pixel in the channel order of the color mode. The
+    /// packed [`ColorMode::Rgb565`] and [`ColorMode::Rgba4444`] modes take two bytes per
+    /// pixel, see their documentation for the bit layout
+    pub fn data(&self) -> &'a [u8] {
+        self.frame_data
+    }
+
+    /// Copy the frame data into an owned [`Frame`]
+    pub fn to_frame(&self) -> Frame {
+        Frame::new_from_decoder(
+            self.timestamp,
+            self.color_mode,
+            self.frame_data.to_vec(),
+            self.dimensions,
+        )
+    }
+}
+
+impl<'a> Debug for FrameRef<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FrameRef {{ timestamp: {}, frame_data: {}b }}",
+            self.timestamp,
+            self.frame_data.len()
+        )
+    }
+}
@@ -0,0 +1,727 @@
+use std::{
+    borrow::Cow,
+    panic,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use libwebp_sys as webp;
+
+use crate::{
+    encoder::{MuxWrapper, PictureWrapper},
+    encoder_config::check_range,
+    BlendMode, ColorMode, DisposeMode, EncoderOptions, EncodingConfig, Error, FrameView, WebPData,
+};
+
+#[allow(unused_imports)]
+use crate::Encoder; // needed by docs
+
+/// Placement of a fragment on the animation canvas, see [`FrameBuilder::add_fragment`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FragmentRect {
+    /// Distance from the left edge of the canvas, in pixels. Must be even
+    pub x_offset: u32,
+    /// Distance from the top edge of the canvas, in pixels. Must be even
+    pub y_offset: u32,
+    /// Width of the fragment, in pixels
+    pub width: u32,
+    /// Height of the fragment, in pixels
+    pub height: u32,
+}
+
+/// A low-level builder for webp animations with explicitly placed frames
+///
+/// Unlike [`Encoder`], which picks sub-rectangles, blending and disposal of the frames on
+/// its own, each fragment is encoded into its own bitstream and stored exactly as given:
+/// at its [`FragmentRect`] on the canvas, with its duration, [`BlendMode`] and
+/// [`DisposeMode`]. Fragments can be read back with
+/// [`Demuxer::fragments`](crate::Demuxer::fragments)
+///
+/// As the fragments are independent, they can be encoded concurrently, see
+/// [`FrameBuilder::set_threads`]
+///
+/// ```rust
+/// use webp_animation::prelude::*;
+///
+/// let mut builder = FrameBuilder::new((64, 32)).unwrap();
+/// let config = EncodingConfig::new_lossy(75.);
+///
+/// // a full canvas background, then a 16x16 sprite drawn on top of it
+/// builder.add_fragment(
+///     &[255u8; 64 * 32 * 4],
+///     FragmentRect { x_offset: 0, y_offset: 0, width: 64, height: 32 },
+///     100,
+///     BlendMode::NoBlend,
+///     DisposeMode::None,
+///     &config,
+/// ).unwrap();
+/// builder.add_fragment(
+///     &[0u8; 16 * 16 * 4],
+///     FragmentRect { x_offset: 8, y_offset: 8, width: 16, height: 16 },
+///     100,
+///     BlendMode::Blend,
+///     DisposeMode::Background,
+///     &config,
+/// ).unwrap();
+///
+/// let webp_data = builder.finalize().unwrap();
+/// let demuxer = Demuxer::new(&webp_data).unwrap();
+/// assert_eq!(demuxer.fragments().nth(1).unwrap().offset(), (8, 8));
+/// ```
+pub struct FrameBuilder {
+    dimensions: (u32, u32),
+    options: EncoderOptions,
+    fragments: Vec<EncodedFragment>,
+    pool: Option<EncoderPool>,
+}
+
+struct EncodedFragment {
+    bitstream: Vec<u8>,
+    rect: FragmentRect,
+    duration: i32,
+    blend_mode: BlendMode,
+    dispose_mode: DisposeMode,
+}
+
+impl FrameBuilder {
+    /// Construct a new builder with default options for canvas dimensions (`width`, `height`)
+    pub fn new(dimensions: (u32, u32)) -> Result<Self, Error> {
+        FrameBuilder::new_with_options(dimensions, Default::default())
+    }
+
+    /// Construct a new builder with custom options for canvas dimensions (`width`, `height`)
+    ///
+    /// Of the [`EncoderOptions`], `anim_params`, `color_mode`, `metadata` and `threads`
+    /// apply. Frames are encoded with the config given to [`FrameBuilder::add_fragment`]
+    pub fn new_with_options(
+        dimensions: (u32, u32),
+        options: EncoderOptions,
+    ) -> Result<Self, Error> {
+        if dimensions.0 == 0 || dimensions.1 == 0 {
+            return Err(Error::DimensionsMustbePositive);
+        }
+
+        options.validate()?;
+
+        let pool = match options.threads {
+            0 | 1 => None,
+            threads => Some(EncoderPool::new(threads)),
+        };
+
+        Ok(Self {
+            dimensions,
+            options,
+            fragments: Vec::new(),
+            pool,
+        })
+    }
+
+    /// Encode the fragments added after this call on `threads` worker threads
+    ///
+    /// [`FrameBuilder::add_fragment`] then only validates the fragment and queues a copy of
+    /// its pixels, encoding failures are returned by [`FrameBuilder::finalize`]. The queue
+    /// holds at most `threads` fragments, once it is full `add_fragment` blocks until a
+    /// worker is free. Fragments are encoded independently, so the output is identical to
+    /// encoding on the calling thread, which is the default (`threads` of `0` or `1`, unless
+    /// set in [`EncoderOptions::threads`])
+    ///
+    /// Waits for fragments queued with a previous setting, returning their encoding error
+    ///
+    /// ```rust
+    /// use webp_animation::prelude::*;
+    ///
+    /// let mut builder = FrameBuilder::new((64, 64)).unwrap();
+    /// builder.set_threads(4).unwrap();
+    ///
+    /// let config = EncodingConfig::new_lossy(75.);
+    /// let rect = FragmentRect { x_offset: 0, y_offset: 0, width: 64, height: 64 };
+    /// for i in 0..8 {
+    ///     let data = vec![i * 30; 64 * 64 * 4];
+    ///     builder
+    ///         .add_fragment(&data, rect, 100, BlendMode::NoBlend, DisposeMode::None, &config)
+    ///         .unwrap();
+    /// }
+    ///
+    /// let webp_data = builder.finalize().unwrap();
+    /// assert_eq!(Demuxer::new(&webp_data).unwrap().frame_count(), 8);
+    /// ```
+    pub fn set_threads(&mut self, threads: usize) -> Result<(), Error> {
+        self.finish_pool()?;
+        if threads > 1 {
+            self.pool = Some(EncoderPool::new(threads));
+        }
+        Ok(())
+    }
+
+    /// Encode a fragment and append it to the animation
+    ///
+    /// Inputs
+    /// * `data` is an array of `rect.width` * `rect.height` pixels in [`ColorMode`] format
+    ///   set by [`EncoderOptions`] ([`ColorMode::Rgba`] by default)
+    /// * `rect` places the fragment on the canvas. The fragment must fit the canvas, and
+    ///   the offsets must be even, as the webp format stores them halved
+    /// * `duration` of the frame in milliseconds, at most 16777215 (24 bits)
+    /// * `blend_mode` and `dispose_mode` of the frame
+    /// * `config` to encode the fragment with
+    ///
+    /// [`ColorMode`]: crate::ColorMode
+    /// [`ColorMode::Rgba`]: crate::ColorMode::Rgba
+    pub fn add_fragment(
+        &mut self,
+        data: &[u8],
+        rect: FragmentRect,
+        duration: i32,
+        blend_mode: BlendMode,
+        dispose_mode: DisposeMode,
+        config: &EncodingConfig,
+    ) -> Result<(), Error> {
+        self.add_fragment_data(
+            Cow::Borrowed(data),
+            rect,
+            duration,
+            blend_mode,
+            dispose_mode,
+            config,
+        )
+    }
+
+    /// [`FrameBuilder::add_fragment`], without copying `data` for the workers if it is owned
+    pub(crate) fn add_fragment_data(
+        &mut self,
+        data: Cow<[u8]>,
+        rect: FragmentRect,
+        duration: i32,
+        blend_mode: BlendMode,
+        dispose_mode: DisposeMode,
+        config: &EncodingConfig,
+    ) -> Result<(), Error> {
+        let (width, height) = self.dimensions;
+        if rect.width == 0
+            || rect.height == 0
+            || rect.x_offset as u64 + rect.width as u64 > width as u64
+            || rect.y_offset as u64 + rect.height as u64 > height as u64
+        {
+            return Err(Error::InvalidFragmentRect(rect));
+        }
+
+        if rect.x_offset % 2 != 0 || rect.y_offset % 2 != 0 {
+            return Err(Error::OddFragmentOffset(rect.x_offset, rect.y_offset));
+        }
+
+        check_range("duration", duration, 0..=0xff_ffff)?;
+
+        let stride = rect.width as usize * self.options.color_mode.size();
+        let expected_len = stride * rect.height as usize;
+        if data.len() != expected_len {
+            return Err(Error::BufferSizeFailed(expected_len, data.len()));
+        }
+
+        let index = self.fragments.len();
+        let color_mode = self.options.color_mode;
+        let bitstream = match &mut self.pool {
+            Some(pool) => {
+                // fail early, the worker would only report this at finalize
+                config.validate()?;
+                pool.push(EncodeJob {
+                    index,
+                    data: data.into_owned(),
+                    dimensions: (rect.width, rect.height),
+                    color_mode,
+                    config: config.clone(),
+                });
+                Vec::new()
+            }
+            None => encode_fragment(index, &data, (rect.width, rect.height), color_mode, config)?,
+        };
+
+        self.fragments.push(EncodedFragment {
+            bitstream,
+            rect,
+            duration,
+            blend_mode,
+            dispose_mode,
+        });
+
+        Ok(())
+    }
+
+    /// Append an already encoded fragment, with a `rect` and `duration` known to be valid
+    pub(crate) fn add_encoded(
+        &mut self,
+        bitstream: Vec<u8>,
+        rect: FragmentRect,
+        duration: i32,
+        blend_mode: BlendMode,
+        dispose_mode: DisposeMode,
+    ) {
+        self.fragments.push(EncodedFragment {
+            bitstream,
+            rect,
+            duration,
+            blend_mode,
+            dispose_mode,
+        });
+    }
+
+    /// Assemble the fragments into an animation and return encoded bytes in a [`WebPData`]
+    ///
+    /// If [`EncoderOptions::metadata`] has been set, the chunks are written into the output
+    pub fn finalize(mut self) -> Result<WebPData, Error> {
+        if self.fragments.is_empty() {
+            return Err(Error::NoFramesAdded);
+        }
+
+        self.finish_pool()?;
+
+        let mux_failed = |function: &str, error: webp::WebPMuxError| {
+            Error::EncoderAssmebleFailed(format!("{} failed: WebPMuxError {}", function, error))
+        };
+
+        let mux = MuxWrapper::new_empty()
+            .ok_or_else(|| mux_failed("WebPMuxNew", webp::WEBP_MUX_MEMORY_ERROR))?;
+
+        for fragment in &self.fragments {
+            let frame = webp::WebPMuxFrameInfo {
+                bitstream: webp::WebPData {
+                    bytes: std::ptr::null(),
+                    size: 0,
+                },
+                x_offset: fragment.rect.x_offset as i32,
+                y_offset: fragment.rect.y_offset as i32,
+                duration: fragment.duration,
+                id: webp::WEBP_CHUNK_ANMF,
+                dispose_method: match fragment.dispose_mode {
+                    DisposeMode::None => webp::WEBP_MUX_DISPOSE_NONE,
+                    DisposeMode::Background => webp::WEBP_MUX_DISPOSE_BACKGROUND,
+                },
+                blend_method: match fragment.blend_mode {
+                    BlendMode::Blend => webp::WEBP_MUX_BLEND,
+                    BlendMode::NoBlend => webp::WEBP_MUX_NO_BLEND,
+                },
+                pad: [0],
+            };
+
+            mux.push_frame(&fragment.bitstream, frame)
+                .map_err(|e| mux_failed("WebPMuxPushFrame", e))?;
+        }
+
+        mux.set_animation_params(self.options.anim_params.to_webp())
+            .map_err(|e| mux_failed("WebPMuxSetAnimationParams", e))?;
+
+        mux.set_canvas_size(self.dimensions)
+            .map_err(|e| mux_failed("WebPMuxSetCanvasSize", e))?;
+
+        for (fourcc, chunk) in self.options.metadata.chunks() {
+            mux.set_chunk(fourcc, chunk)
+                .map_err(|_| Error::MetadataWriteFailed)?;
+        }
+
+        let data = mux
+            .assemble()
+            .map_err(|e| mux_failed("WebPMuxAssemble", e))?;
+
+        log::trace!(
+            "Assembled {} fragments, output binary size {} bytes",
+            self.fragments.len(),
+            data.len()
+        );
+
+        Ok(data)
+    }
+
+    fn finish_pool(&mut self) -> Result<(), Error> {
+        if let Some(mut pool) = self.pool.take() {
+            for (index, bitstream) in pool.finish()? {
+                self.fragments[index].bitstream = bitstream;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn encode_fragment(
+    index: usize,
+    data: &[u8],
+    dimensions: (u32, u32),
+    color_mode: ColorMode,
+    config: &EncodingConfig,
+) -> Result<Vec<u8>, Error> {
+    let config = config.to_config_container()?;
+    let mut picture = PictureWrapper::new(dimensions)?;
+    let view = FrameView {
+        data,
+        stride: dimensions.0 as usize * color_mode.size(),
+        offset: (0, 0),
+        dimensions,
+    };
+    picture.set_data(&view, color_mode)?;
+
+    let bitstream = picture
+        .encode(&config)
+        .map_err(|error| Error::FragmentEncodeFailed(index, error))?;
+
+    log::trace!("Encoded fragment {}, {} bytes", index, bitstream.len());
+
+    Ok(bitstream)
+}
+
+struct EncodeJob {
+    index: usize,
+    data: Vec<u8>,
+    dimensions: (u32, u32),
+    color_mode: ColorMode,
+    config: EncodingConfig,
+}
+
+type EncodeResult = (usize, Result<Vec<u8>, Error>);
+
+/// Worker threads encoding queued fragments, see [`FrameBuilder::set_threads`]
+struct EncoderPool {
+    jobs: Option<mpsc::SyncSender<EncodeJob>>,
+    results: mpsc::Receiver<EncodeResult>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl EncoderPool {
+    fn new(threads: usize) -> Self {
+        // bound the number of pixel copies waiting for a worker
+        let (jobs, job_receiver) = mpsc::sync_channel::<EncodeJob>(threads);
+        // the bitstreams are kept until finish anyway, so results are never waited on
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+                thread::spawn(move || loop {
+                    // the lock is released before encoding
+                    let job = job_receiver.lock().unwrap().recv();
+                    let job = match job {
+                        Ok(job) => job,
+                        Err(_) => break, // all jobs done
+                    };
+
+                    let result = encode_fragment(
+                        job.index,
+                        &job.data,
+                        job.dimensions,
+                        job.color_mode,
+                        &job.config,
+                    );
+                    if result_sender.send((job.index, result)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+        }
+    }
+
+    fn push(&mut self, job: EncodeJob) {
+        // workers only stop early by panicking, which is propagated by finish
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+
+    /// Wait for all queued jobs, and return the bitstreams ordered by fragment index
+    fn finish(&mut self) -> Result<Vec<(usize, Vec<u8>)>, Error> {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            if let Err(panic) = worker.join() {
+                panic::resume_unwind(panic);
+            }
+        }
+
+        let mut results: Vec<_> = self.results.try_iter().collect();
+        results.sort_by_key(|(index, _)| *index);
+        results
+            .into_iter()
+            .map(|(index, result)| result.map(|bitstream| (index, bitstream)))
+            .collect()
+    }
+}
+
+impl Drop for EncoderPool {
+    fn drop(&mut self) {
+        // let the workers run out of jobs, nothing is waiting for the results
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AnimParams, ColorMode, Decoder, DecoderOptions, Demuxer, EncodingType, LoopCount};
+
+    fn lossless() -> EncodingConfig {
+        EncodingConfig {
+            encoding_type: EncodingType::new_lossless(),
+            ..Default::default()
+        }
+    }
+
+    fn rect(x_offset: u32, y_offset: u32, width: u32, height: u32) -> FragmentRect {
+        FragmentRect {
+            x_offset,
+            y_offset,
+            width,
+            height,
+        }
+    }
+
+    fn solid(color: [u8; 4], (width, height): (u32, u32)) -> Vec<u8> {
+        color.repeat(width as usize * height as usize)
+    }
+
+    #[test]
+    fn test_frame_builder() {
+        let mut builder = FrameBuilder::new_with_options(
+            (40, 30),
+            EncoderOptions {
+                anim_params: AnimParams {
+                    loop_count: LoopCount::new(3).unwrap(),
+                    background_color: [10, 20, 30, 40],
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let fragments = [
+            (
+                red,
+                rect(0, 0, 40, 30),
+                BlendMode::NoBlend,
+                DisposeMode::None,
+            ),
+            (
+                blue,
+                rect(10, 20, 6, 4),
+                BlendMode::Blend,
+                DisposeMode::Background,
+            ),
+            (
+                blue,
+                rect(0, 2, 40, 2),
+                BlendMode::NoBlend,
+                DisposeMode::None,
+            ),
+        ];
+
+        for (i, (color, rect, blend_mode, dispose_mode)) in fragments.iter().enumerate() {
+            let data = solid(*color, (rect.width, rect.height));
+            let duration = 100 * (i as i32 + 1);
+            builder
+                .add_fragment(
+                    &data,
+                    *rect,
+                    duration,
+                    *blend_mode,
+                    *dispose_mode,
+                    &lossless(),
+                )
+                .unwrap();
+        }
+
+        let webp_data = builder.finalize().unwrap();
+
+        // the fragments are stored exactly as given
+        let demuxer = Demuxer::new(&webp_data).unwrap();
+        assert_eq!(demuxer.dimensions(), (40, 30));
+        assert_eq!(demuxer.fragments().count(), 3);
+        for (fragment, (_, rect, blend_mode, dispose_mode)) in
+            demuxer.fragments().zip(fragments.iter())
+        {
+            assert_eq!(fragment.offset(), (rect.x_offset, rect.y_offset));
+            assert_eq!(fragment.dimensions(), (rect.width, rect.height));
+            assert_eq!(fragment.duration(), 100 * (fragment.index() as i32 + 1));
+            assert_eq!(fragment.blend_mode(), *blend_mode);
+            assert_eq!(fragment.dispose_mode(), *dispose_mode);
+        }
+
+        let decoder = Decoder::new_with_options(
+            &webp_data,
+            DecoderOptions {
+                color_mode: ColorMode::Rgba,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(decoder.info().loop_count.to_webp(), 3);
+        assert_eq!(decoder.info().background_color, [10, 20, 30, 40]);
+
+        let frames: Vec<_> = decoder.into_iter().collect();
+        let pixel = |frame: usize, x: usize, y: usize| {
+            let i = (y * 40 + x) * 4;
+            &frames[frame].data()[i..i + 4]
+        };
+
+        assert_eq!(pixel(0, 12, 21), red);
+        assert_eq!(pixel(1, 12, 21), blue);
+        assert_eq!(pixel(1, 0, 0), red);
+        // the sprite has been disposed to transparent
+        assert_eq!(pixel(2, 12, 21), [0, 0, 0, 0]);
+        assert_eq!(pixel(2, 0, 3), blue);
+        assert_eq!(pixel(2, 0, 0), red);
+    }
+
+    #[test]
+    fn test_frame_builder_threads() {
+        let config = EncodingConfig {
+            method: 6,
+            ..EncodingConfig::new_lossy(60.)
+        };
+
+        let build = |threads| {
+            let mut builder = FrameBuilder::new((64, 48)).unwrap();
+            builder.set_threads(threads).unwrap();
+            for i in 0..12u32 {
+                let rect = rect(i * 2, i * 2, 64 - i * 4, 48 - i * 4);
+                let data: Vec<u8> = (0..rect.width * rect.height * 4)
+                    .map(|v| (v * (i + 1) / 3) as u8)
+                    .collect();
+                let config = if i % 3 == 0 {
+                    lossless()
+                } else {
+                    config.clone()
+                };
+                builder
+                    .add_fragment(
+                        &data,
+                        rect,
+                        50,
+                        BlendMode::NoBlend,
+                        DisposeMode::None,
+                        &config,
+                    )
+                    .unwrap();
+
+                if i == 5 {
+                    // switching waits for the fragments queued so far
+                    builder.set_threads(threads + 1).unwrap();
+                }
+            }
+            builder.finalize().unwrap()
+        };
+
+        let sequential = build(1);
+        for &threads in &[2, 5] {
+            assert!(*build(threads) == *sequential);
+        }
+
+        // encoding failures are returned by finalize, with the index of the fragment
+        let mut builder = FrameBuilder::new((20000, 2)).unwrap();
+        builder.set_threads(2).unwrap();
+        for &width in &[16, 20000, 16] {
+            builder
+                .add_fragment(
+                    &solid([255; 4], (width, 2)),
+                    rect(0, 0, width, 2),
+                    50,
+                    BlendMode::NoBlend,
+                    DisposeMode::None,
+                    &config,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            builder.finalize().unwrap_err(),
+            Error::FragmentEncodeFailed(1, crate::EncodingError::BadDimension)
+        );
+
+        // invalid configs are still rejected by add_fragment
+        let mut builder = FrameBuilder::new((4, 4)).unwrap();
+        builder.set_threads(2).unwrap();
+        let invalid = EncodingConfig {
+            method: 7,
+            ..Default::default()
+        };
+        assert_eq!(
+            builder
+                .add_fragment(
+                    &solid([0; 4], (4, 4)),
+                    rect(0, 0, 4, 4),
+                    50,
+                    BlendMode::NoBlend,
+                    DisposeMode::None,
+                    &invalid,
+                )
+                .unwrap_err(),
+            Error::InvalidConfigField {
+                field: "method",
+                value: "7".to_string(),
+                allowed: "0..=6".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_frame_builder_failures() {
+        assert_eq!(
+            FrameBuilder::new((0, 10)).err().unwrap(),
+            Error::DimensionsMustbePositive
+        );
+
+        let builder = FrameBuilder::new((40, 30)).unwrap();
+        assert_eq!(builder.finalize().unwrap_err(), Error::NoFramesAdded);
+
+        let mut builder = FrameBuilder::new((40, 30)).unwrap();
+        let mut add = |rect: FragmentRect, duration| {
+            let data = solid([0; 4], (rect.width, rect.height));
+            builder.add_fragment(
+                &data,
+                rect,
+                duration,
+                BlendMode::Blend,
+                DisposeMode::None,
+                &lossless(),
+            )
+        };
+
+        for invalid in &[rect(0, 0, 0, 10), rect(2, 0, 40, 10), rect(0, 20, 40, 12)] {
+            assert_eq!(
+                add(*invalid, 100).unwrap_err(),
+                Error::InvalidFragmentRect(*invalid)
+            );
+        }
+        assert_eq!(
+            add(rect(1, 4, 10, 10), 100).unwrap_err(),
+            Error::OddFragmentOffset(1, 4)
+        );
+        assert_eq!(
+            add(rect(0, 0, 10, 10), 0x100_0000).unwrap_err(),
+            Error::InvalidConfigField {
+                field: "duration",
+                value: "16777216".to_string(),
+                allowed: "0..=16777215".to_string(),
+            }
+        );
+        assert!(add(rect(30, 20, 10, 10), 0xff_ffff).is_ok());
+
+        assert_eq!(
+            builder
+                .add_fragment(
+                    &[0u8; 10],
+                    rect(0, 0, 2, 2),
+                    100,
+                    BlendMode::Blend,
+                    DisposeMode::None,
+                    &lossless(),
+                )
+                .unwrap_err(),
+            Error::BufferSizeFailed(16, 10)
+        );
+    }
+}
@@ -10,18 +10,38 @@
 //! # Usage
 //! Have a look at [`Decoder`] and [`Encoder`] for use-case specific examples.
 
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    io::ErrorKind,
+    time::Duration,
+};
 
+use libwebp_sys as webp;
+
+mod animation_info;
+mod compositor;
 mod decoder;
+mod demuxer;
+mod encode_report;
 mod encoder;
 mod encoder_config;
 mod frame;
+mod frame_builder;
+mod poster;
+mod streaming_decoder;
+mod threaded_encoder;
 mod webp_data;
 
+pub use animation_info::*;
 pub use decoder::*;
+pub use demuxer::*;
+pub use encode_report::*;
 pub use encoder::*;
 pub use encoder_config::*;
 pub use frame::*;
+pub use frame_builder::*;
+pub use poster::*;
+pub use streaming_decoder::*;
 pub use webp_data::*;
 
 pub mod prelude {
@@ -29,10 +49,17 @@ pub mod prelude {
     pub use crate::ColorMode;
 
     // decoder
-    pub use crate::{Decoder, DecoderOptions};
+    pub use crate::{
+        AnimationInfo, CropRect, Decoder, DecoderLimits, DecoderOptions, Demuxer, OwnedDecoder,
+        StreamingDecoder, StreamingStatus,
+    };
 
     // encoder
-    pub use crate::{Encoder, EncoderOptions, EncodingConfig, EncodingType, LossyEncodingConfig};
+    pub use crate::{
+        AnimParams, BlendMode, ControlFlow, DisposeMode, EncodeReport, Encoder, EncoderOptions,
+        EncodingConfig, EncodingType, FragmentRect, FrameBuilder, FrameStats, FrameView, ImageHint,
+        LoopCount, LosslessEncodingConfig, LossyEncodingConfig, Metadata, Preset, Progress,
+    };
 }
 
 /// Color Mode that configures the output type of [`Decoder`] [`Frame`]'s
@@ -46,16 +73,43 @@ pub enum ColorMode {
     Bgra,
     // Bgr (blue, green, red) - no alpha
     Bgr,
+    /// Rgba (red, green, blue, alpha) with color channels premultiplied by alpha
+    RgbaPremultiplied,
+    /// Bgra (blue, green, red, alpha) with color channels premultiplied by alpha
+    BgraPremultiplied,
+    /// Argb (alpha, red, green, blue) with color channels premultiplied by alpha
+    ArgbPremultiplied,
+    /// Rgb565, 5 bits red, 6 bits green and 5 bits blue packed into two bytes - no alpha.
+    /// First byte holds red and the high bits of green, second byte the low bits of green
+    /// and blue (same byte order as libwebp `MODE_RGB_565`)
+    Rgb565,
+    /// Rgba4444, 4 bits per channel packed into two bytes. First byte holds red and green,
+    /// second byte blue and alpha, high nibble first (same byte order as libwebp
+    /// `MODE_RGBA_4444`)
+    Rgba4444,
 }
 
 impl ColorMode {
     /// Return the pixel bytesize for the color mode
     pub fn size(&self) -> usize {
         match self {
+            Self::Rgb565 | Self::Rgba4444 => 2,
             Self::Rgb | Self::Bgr => 3,
-            Self::Rgba | Self::Bgra => 4,
+            Self::Rgba
+            | Self::Bgra
+            | Self::RgbaPremultiplied
+            | Self::BgraPremultiplied
+            | Self::ArgbPremultiplied => 4,
         }
     }
+
+    /// Return true if color channels are premultiplied by alpha
+    pub fn is_premultiplied(&self) -> bool {
+        matches!(
+            self,
+            Self::RgbaPremultiplied | Self::BgraPremultiplied | Self::ArgbPremultiplied
+        )
+    }
 }
 
 /// Error type produced by `webp_animation` code
@@ -64,27 +118,49 @@ pub enum Error {
     /// Initializing webp options failed, internal (memory allocation?) failure
     OptionsInitFailed,
 
-    /// Decoder init failed, input contains wrong bytes
-    DecodeFailed,
+    /// Decoder init failed, input contains wrong bytes. [`DecodingError`] tells the reason
+    /// reported by libwebp
+    DecodeFailed(DecodingError),
 
     /// Decoder could not get metadata of webp stream. Corrupt data?
     DecoderGetInfoFailed,
 
-    /// Webp stream contains too large canvas. For now, size is limited to 3840 * 2160 pixels
-    /// See `MAX_CANVAS_SIZE` variable from code
+    /// Webp stream contains too large canvas (width, height, limit). By default, size is
+    /// limited to 3840 * 2160 pixels, see [`DecoderLimits::max_canvas_pixels`]
     TooLargeCanvas(u32, u32, usize),
 
     /// Encoder create failed. Wrong options combination?
     EncoderCreateFailed,
 
-    /// Data input buffer size did not match encoder metadata (width * height * 4)
+    /// Data input buffer size did not match encoder metadata (width * height * [`ColorMode::size`]),
+    /// or is too small for the [`FrameView`] (expected, received)
     BufferSizeFailed(usize, usize),
 
+    /// Row stride of the input is smaller than the row (up to the right edge of the
+    /// [`FrameView`]), or does not fit libwebp (stride, minimum stride)
+    InvalidStride(usize, usize),
+
+    /// [`FrameView`] dimensions do not match the encoder dimensions (view, encoder)
+    WrongFrameDimensions((u32, u32), (u32, u32)),
+
+    /// Fragment is empty or exceeds the animation canvas, see [`FrameBuilder::add_fragment`]
+    InvalidFragmentRect(FragmentRect),
+
+    /// Fragment offset (x, y) is odd, the webp format only stores even offsets
+    OddFragmentOffset(u32, u32),
+
+    /// Encoding the fragment at index failed, see [`FrameBuilder::add_fragment`]
+    FragmentEncodeFailed(usize, EncodingError),
+
     /// Raw data could not be converted into webp frame by underlying libwebp library
-    PictureImportFailed,
+    PictureImportFailed(EncodingError),
 
-    /// Frame could not be added to webp stream by underlying libwebp library
-    EncoderAddFailed,
+    /// Frame could not be added to webp stream by underlying libwebp library. `message` is
+    /// the error message of the libwebp animation encoder
+    EncoderAddFailed {
+        error: EncodingError,
+        message: String,
+    },
 
     /// Underlying data is in different color mode
     WrongColorMode(ColorMode, ColorMode),
@@ -95,8 +171,11 @@ pub enum Error {
     /// Timestamp must be higher or equal to the previous frame timestamp
     TimestampMustBeEqualOrHigherThanPrevious(i32, i32),
 
-    /// Encoder webp assembly failed
-    EncoderAssmebleFailed,
+    /// Encoder webp assembly failed, with the error message of the libwebp animation encoder
+    EncoderAssmebleFailed(String),
+
+    /// Writing metadata chunks into the encoded webp data failed
+    MetadataWriteFailed,
 
     /// Supplied dimensions must be positive
     DimensionsMustbePositive,
@@ -109,29 +188,225 @@ pub enum Error {
 
     /// Encoder config validation failed
     InvalidEncodingConfig,
+
+    /// Encoder config or options field is out of range. `allowed` describes the valid values
+    InvalidConfigField {
+        field: &'static str,
+        value: String,
+        allowed: String,
+    },
+
+    /// Input stream ended before the animation was complete
+    UnexpectedEndOfStream,
+
+    /// Reading from input stream failed
+    ReadFailed(ErrorKind),
+
+    /// Requested frame index is past the last frame (index, frame count)
+    FrameIndexOutOfBounds(usize, usize),
+
+    /// Webp stream contains too many frames (frame count, limit),
+    /// see [`DecoderLimits::max_frame_count`]
+    TooManyFrames(u32, u32),
+
+    /// Animation is too long (duration in milliseconds, limit),
+    /// see [`DecoderLimits::max_duration_ms`]
+    TooLongDuration(u64, u64),
+
+    /// Decoded frames would take too much memory (bytes, limit),
+    /// see [`DecoderLimits::max_decoded_bytes`]
+    TooManyDecodedBytes(usize, usize),
+
+    /// Decoding a frame took too long (decode time, limit),
+    /// see [`DecoderLimits::max_frame_decode_time`]
+    FrameDecodeTimeExceeded(Duration, Duration),
+
+    /// Decoding the frame at `index` failed, input is likely corrupt. `timestamp` is the
+    /// timestamp of the last successfully decoded frame, zero if there is none
+    FrameDecodeFailed {
+        index: usize,
+        timestamp: i32,
+        error: DecodingError,
+    },
+
+    /// Crop rectangle is empty or exceeds the animation canvas, see [`DecoderOptions::crop`]
+    InvalidCrop(CropRect),
+
+    /// Output size has a zero dimension (width, height), see [`DecoderOptions::output_size`]
+    InvalidOutputSize(u32, u32),
+
+    /// Encoding was cancelled by the progress callback, see [`Encoder::set_progress_callback`]
+    Cancelled,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::OptionsInitFailed => write!(f, "OptionsInitFailed: Initializing webp options failed, internal (memory allocation?) failure"),
-            Error::DecodeFailed => write!(f, "DecodeFailed: Could not decode input bytes, possibly malformed data"),
+            Error::DecodeFailed(error) => write!(f, "DecodeFailed: Could not decode input bytes: {}", error),
             Error::DecoderGetInfoFailed => write!(f, "DecoderGetInfoFailed: Decoder could not get metadata of webp stream. Corrupt data?"),
-            Error::TooLargeCanvas(width, height, max_size) => write!(f, "TooLargeCanvas: Decodable canvas is too large ({} x {} = {} pixels). For now, size is limited to 3840 * 2160 = {} pixels", width, height, width * height, max_size),
+            Error::TooLargeCanvas(width, height, max_size) => write!(f, "TooLargeCanvas: Decodable canvas is too large ({} x {} = {} pixels). Size is limited to {} pixels (max_canvas_pixels)", width, height, *width as usize * *height as usize, max_size),
             Error::EncoderCreateFailed => write!(f, "EncoderCreateFailed: Encoder create failed. Wrong options combination?"),
-            Error::BufferSizeFailed(expected, received) => write!(f, "BufferSizeFailed: Expected (width * height * 4 = {}) bytes as input buffer, got {} bytes", expected, received),
-            Error::PictureImportFailed => write!(f, "PictureImportFailed: Raw data could not be converted into webp frame by underlying libwebp library"),
-            Error::EncoderAddFailed => write!(f, "EncoderAddFailed: Frame could not be added to webp stream by underlying libwebp library"),
+            Error::BufferSizeFailed(expected, received) => write!(f, "BufferSizeFailed: Expected {} bytes as input buffer, got {} bytes", expected, received),
+            Error::InvalidStride(stride, min_stride) => write!(f, "InvalidStride: Stride of {} bytes is invalid, must be at least {} bytes", stride, min_stride),
+            Error::WrongFrameDimensions(view, encoder) => write!(f, "WrongFrameDimensions: Frame is {} x {}, encoder expects {} x {}", view.0, view.1, encoder.0, encoder.1),
+            Error::InvalidFragmentRect(rect) => write!(f, "InvalidFragmentRect: Fragment {}x{} at ({}, {}) is empty or exceeds the canvas", rect.width, rect.height, rect.x_offset, rect.y_offset),
+            Error::OddFragmentOffset(x, y) => write!(f, "OddFragmentOffset: Fragment offset ({}, {}) must be even", x, y),
+            Error::FragmentEncodeFailed(index, error) => write!(f, "FragmentEncodeFailed: Encoding fragment {} failed: {}", index, error),
+            Error::PictureImportFailed(error) => write!(f, "PictureImportFailed: Raw data could not be converted into webp frame by underlying libwebp library: {}", error),
+            Error::EncoderAddFailed { error, message } => write!(f, "EncoderAddFailed: Frame could not be added to webp stream by underlying libwebp library: {} ({})", message, error),
             Error::WrongColorMode(requested, expected) => write!(f, "WrongColorMode: Requested image in {:?} format but underlying is stored as {:?}", expected, requested),
             Error::TimestampMustBeHigherThanPrevious(requested, previous) => write!(f, "TimestampMustBeHigherThanPrevious: Supplied timestamp (got {}) must be higher than {}", requested, previous),
             Error::TimestampMustBeEqualOrHigherThanPrevious(requested, previous) => write!(f, "TimestampMustBeEqualOrHigherThanPrevious: Supplied timestamp (got {}) must be higher or equal to {}", requested, previous),
-            Error::EncoderAssmebleFailed => write!(f, "EncoderAssmebleFailed: Encoder webp assembly failed"),
+            Error::EncoderAssmebleFailed(message) => write!(f, "EncoderAssmebleFailed: Encoder webp assembly failed: {}", message),
+            Error::MetadataWriteFailed => write!(f, "MetadataWriteFailed: Writing metadata chunks into the encoded webp data failed"),
             Error::DimensionsMustbePositive => write!(f, "DimensionsMustbePositive: Supplied dimensions must be positive"),
             Error::NoFramesAdded => write!(f, "NoFramesAdded: No frames have been added yet"),
             Error::ZeroSizeBuffer => write!(f, "ZeroSizeBuffer: Buffer contains no data"),
-            Error::InvalidEncodingConfig => write!(f, "InvalidEncodingConfig: encoding configuration validation failed")
+            Error::InvalidEncodingConfig => write!(f, "InvalidEncodingConfig: encoding configuration validation failed"),
+            Error::InvalidConfigField { field, value, allowed } => write!(f, "InvalidConfigField: {} is {}, allowed values are {}", field, value, allowed),
+            Error::UnexpectedEndOfStream => write!(f, "UnexpectedEndOfStream: Input stream ended before the animation was complete"),
+            Error::ReadFailed(kind) => write!(f, "ReadFailed: Reading from input stream failed ({:?})", kind),
+            Error::FrameIndexOutOfBounds(index, frame_count) => write!(f, "FrameIndexOutOfBounds: Requested frame {}, but animation has {} frames", index, frame_count),
+            Error::TooManyFrames(frame_count, limit) => write!(f, "TooManyFrames: Animation has {} frames, limit is {} (max_frame_count)", frame_count, limit),
+            Error::TooLongDuration(duration, limit) => write!(f, "TooLongDuration: Animation is {} ms long, limit is {} ms (max_duration_ms)", duration, limit),
+            Error::TooManyDecodedBytes(bytes, limit) => write!(f, "TooManyDecodedBytes: Decoded frames take {} bytes, limit is {} bytes (max_decoded_bytes)", bytes, limit),
+            Error::FrameDecodeTimeExceeded(elapsed, limit) => write!(f, "FrameDecodeTimeExceeded: Decoding a frame took {:?}, limit is {:?} (max_frame_decode_time)", elapsed, limit),
+            Error::FrameDecodeFailed { index, timestamp, error } => write!(f, "FrameDecodeFailed: Decoding frame {} (after timestamp {} ms) failed: {}", index, timestamp, error),
+            Error::InvalidCrop(crop) => write!(f, "InvalidCrop: Crop rectangle {}x{} at ({}, {}) is empty or exceeds the canvas", crop.width, crop.height, crop.left, crop.top),
+            Error::InvalidOutputSize(width, height) => write!(f, "InvalidOutputSize: Output size {} x {} has a zero dimension", width, height),
+            Error::Cancelled => write!(f, "Cancelled: Encoding was cancelled by the progress callback"),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+/// Reason of a decoding failure, as reported by libwebp (`VP8StatusCode`)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodingError {
+    /// Memory allocation failed
+    OutOfMemory,
+    /// Invalid decoding parameters
+    InvalidParam,
+    /// Input data is malformed
+    BitstreamError,
+    /// Input uses a feature libwebp does not support
+    UnsupportedFeature,
+    /// Incremental decoding was suspended
+    Suspended,
+    /// Decoding was aborted by the user
+    UserAbort,
+    /// Input data is truncated
+    NotEnoughData,
+    /// libwebp failed without reporting a reason
+    Unknown,
+}
+
+impl DecodingError {
+    /// Convert a libwebp status code, `Ok` for `VP8_STATUS_OK`
+    pub(crate) fn check(status: webp::VP8StatusCode) -> Result<(), Self> {
+        Err(match status {
+            webp::VP8_STATUS_OK => return Ok(()),
+            webp::VP8_STATUS_OUT_OF_MEMORY => Self::OutOfMemory,
+            webp::VP8_STATUS_INVALID_PARAM => Self::InvalidParam,
+            webp::VP8_STATUS_UNSUPPORTED_FEATURE => Self::UnsupportedFeature,
+            webp::VP8_STATUS_SUSPENDED => Self::Suspended,
+            webp::VP8_STATUS_USER_ABORT => Self::UserAbort,
+            webp::VP8_STATUS_NOT_ENOUGH_DATA => Self::NotEnoughData,
+            _ => Self::BitstreamError,
+        })
+    }
+}
+
+impl Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "out of memory (VP8_STATUS_OUT_OF_MEMORY)"),
+            Self::InvalidParam => write!(f, "invalid parameter (VP8_STATUS_INVALID_PARAM)"),
+            Self::BitstreamError => write!(f, "malformed data (VP8_STATUS_BITSTREAM_ERROR)"),
+            Self::UnsupportedFeature => {
+                write!(f, "unsupported feature (VP8_STATUS_UNSUPPORTED_FEATURE)")
+            }
+            Self::Suspended => write!(f, "decoding suspended (VP8_STATUS_SUSPENDED)"),
+            Self::UserAbort => write!(f, "aborted by user (VP8_STATUS_USER_ABORT)"),
+            Self::NotEnoughData => write!(f, "truncated data (VP8_STATUS_NOT_ENOUGH_DATA)"),
+            Self::Unknown => write!(f, "unknown error"),
+        }
+    }
+}
+
+/// Reason of an encoding failure, as reported by libwebp (`WebPEncodingError`)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EncodingError {
+    /// Memory allocation failed
+    OutOfMemory,
+    /// Memory allocation for flushing the bitstream failed
+    BitstreamOutOfMemory,
+    /// A pointer parameter is null
+    NullParameter,
+    /// Encoding configuration is invalid
+    InvalidConfiguration,
+    /// Picture has invalid width / height
+    BadDimension,
+    /// Partition is bigger than 512k, try a lower quality or more partitions
+    Partition0Overflow,
+    /// Partition is bigger than 16M
+    PartitionOverflow,
+    /// Writing the bitstream failed
+    BadWrite,
+    /// File would be bigger than 4G
+    FileTooBig,
+    /// Encoding was aborted by the user
+    UserAbort,
+    /// libwebp failed without reporting a reason, see the error message if there is one
+    Unknown,
+}
+
+impl EncodingError {
+    pub(crate) fn from_webp(error: webp::WebPEncodingError) -> Self {
+        match error {
+            webp::VP8_ENC_ERROR_OUT_OF_MEMORY => Self::OutOfMemory,
+            webp::VP8_ENC_ERROR_BITSTREAM_OUT_OF_MEMORY => Self::BitstreamOutOfMemory,
+            webp::VP8_ENC_ERROR_NULL_PARAMETER => Self::NullParameter,
+            webp::VP8_ENC_ERROR_INVALID_CONFIGURATION => Self::InvalidConfiguration,
+            webp::VP8_ENC_ERROR_BAD_DIMENSION => Self::BadDimension,
+            webp::VP8_ENC_ERROR_PARTITION0_OVERFLOW => Self::Partition0Overflow,
+            webp::VP8_ENC_ERROR_PARTITION_OVERFLOW => Self::PartitionOverflow,
+            webp::VP8_ENC_ERROR_BAD_WRITE => Self::BadWrite,
+            webp::VP8_ENC_ERROR_FILE_TOO_BIG => Self::FileTooBig,
+            webp::VP8_ENC_ERROR_USER_ABORT => Self::UserAbort,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "out of memory (VP8_ENC_ERROR_OUT_OF_MEMORY)"),
+            Self::BitstreamOutOfMemory => write!(
+                f,
+                "out of memory while flushing bits (VP8_ENC_ERROR_BITSTREAM_OUT_OF_MEMORY)"
+            ),
+            Self::NullParameter => write!(f, "null parameter (VP8_ENC_ERROR_NULL_PARAMETER)"),
+            Self::InvalidConfiguration => write!(
+                f,
+                "invalid configuration (VP8_ENC_ERROR_INVALID_CONFIGURATION)"
+            ),
+            Self::BadDimension => write!(f, "bad picture dimensions (VP8_ENC_ERROR_BAD_DIMENSION)"),
+            Self::Partition0Overflow => write!(
+                f,
+                "partition #0 is bigger than 512k (VP8_ENC_ERROR_PARTITION0_OVERFLOW)"
+            ),
+            Self::PartitionOverflow => write!(
+                f,
+                "partition is bigger than 16M (VP8_ENC_ERROR_PARTITION_OVERFLOW)"
+            ),
+            Self::BadWrite => write!(f, "writing the bitstream failed (VP8_ENC_ERROR_BAD_WRITE)"),
+            Self::FileTooBig => write!(f, "file is bigger than 4G (VP8_ENC_ERROR_FILE_TOO_BIG)"),
+            Self::UserAbort => write!(f, "aborted by user (VP8_ENC_ERROR_USER_ABORT)"),
+            Self::Unknown => write!(f, "no reason reported"),
+        }
+    }
+}
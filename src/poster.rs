@@ -0,0 +1,164 @@
+use crate::{
+    compositor::Compositor,
+    demuxer::{Demuxer, FrameIndex},
+    ColorMode, DecoderOptions, DecodingError, Error, Frame,
+};
+
+/// Decode only the first frame of webp `buffer`, e.g. for a still preview of an animation
+///
+/// Faster than decoding the first frame with [`Decoder`](crate::Decoder), as only the first
+/// fragment is decoded and no animation decoder is set up. Works for still images, too
+///
+/// ```
+/// # use webp_animation::prelude::*;
+/// #
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let frame = webp_animation::poster(&buffer, ColorMode::Rgba).unwrap();
+///
+/// assert_eq!(frame.dimensions(), (400, 400));
+/// assert_eq!(frame.timestamp(), 40);
+/// ```
+pub fn poster(buffer: &[u8], color_mode: ColorMode) -> Result<Frame, Error> {
+    poster_at(
+        buffer,
+        0,
+        DecoderOptions {
+            color_mode,
+            ..Default::default()
+        },
+    )
+}
+
+/// Decode only the frame at `index` (zero-based) of webp `buffer`
+///
+/// Frames are composited starting from the closest keyframe before `index`, see
+/// [`Decoder::seek_to_frame`](crate::Decoder::seek_to_frame). All of the `options` apply,
+/// so e.g. a thumbnail can be produced with [`DecoderOptions::output_size`]
+///
+/// Returns an [`Error`] if `index` is past the last frame, or in case of a decoding failure
+///
+/// ```
+/// # use webp_animation::prelude::*;
+/// #
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let frame = webp_animation::poster_at(&buffer, 5, DecoderOptions {
+///   output_size: Some((100, 100)),
+///   ..Default::default()
+/// }).unwrap();
+///
+/// assert_eq!(frame.dimensions(), (100, 100));
+/// assert_eq!(frame.timestamp(), 240);
+/// ```
+pub fn poster_at(buffer: &[u8], index: usize, options: DecoderOptions) -> Result<Frame, Error> {
+    if buffer.is_empty() {
+        return Err(Error::ZeroSizeBuffer);
+    }
+
+    let demuxer = Demuxer::new(buffer)?;
+    let frame_count = demuxer.frame_count() as usize;
+    if index >= frame_count {
+        return Err(Error::FrameIndexOutOfBounds(index, frame_count));
+    }
+
+    // prevent too large allocations
+    let limits = &options.limits;
+    limits.check_canvas(demuxer.dimensions())?;
+    let mut compositor = Compositor::new(demuxer.dimensions(), &options)?;
+    limits.check_canvas(compositor.dimensions())?;
+
+    match index {
+        // the first frame is always a keyframe, no need to index the others
+        0 => {
+            let fragment = demuxer
+                .fragment(0)
+                .ok_or(Error::DecodeFailed(DecodingError::BitstreamError))?;
+            compositor.composite(&fragment)?;
+        }
+        _ => compositor.seek(&demuxer, &FrameIndex::new(&demuxer)?, index)?,
+    }
+
+    Ok(compositor.into_frame())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ptr, slice};
+
+    use libwebp_sys as webp;
+
+    use super::*;
+    use crate::Decoder;
+
+    #[test]
+    fn test_poster() {
+        for file in ["./data/animated.webp", "./data/example.webp"] {
+            let buffer = std::fs::read(file).unwrap();
+            let expected: Vec<_> = Decoder::new_with_options(
+                &buffer,
+                DecoderOptions {
+                    color_mode: ColorMode::Bgra,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .into_iter()
+            .collect();
+
+            let frame = poster(&buffer, ColorMode::Bgra).unwrap();
+            assert_eq!(frame.timestamp(), expected[0].timestamp());
+            assert_eq!(frame.color_mode(), ColorMode::Bgra);
+            assert!(frame.data() == expected[0].data());
+
+            for index in [expected.len() - 1, 3] {
+                let options = DecoderOptions {
+                    color_mode: ColorMode::Bgra,
+                    ..Default::default()
+                };
+                let frame = poster_at(&buffer, index, options).unwrap();
+                assert_eq!(frame.timestamp(), expected[index].timestamp());
+                assert!(frame.data() == expected[index].data());
+            }
+
+            assert_eq!(
+                poster_at(&buffer, expected.len(), Default::default()).unwrap_err(),
+                Error::FrameIndexOutOfBounds(expected.len(), expected.len())
+            );
+        }
+
+        assert_eq!(
+            poster(&[], ColorMode::Rgba).unwrap_err(),
+            Error::ZeroSizeBuffer
+        );
+        assert_eq!(
+            poster(&[0u8; 64], ColorMode::Rgba).unwrap_err(),
+            Error::DecodeFailed(DecodingError::BitstreamError)
+        );
+    }
+
+    #[test]
+    fn test_poster_still_image() {
+        let rgba: Vec<u8> = (0..16 * 8)
+            .flat_map(|i| vec![i as u8, (i * 3) as u8, (i * 7) as u8, 255])
+            .collect();
+
+        let buffer = unsafe {
+            let mut output = ptr::null_mut();
+            let size = webp::WebPEncodeLosslessRGBA(rgba.as_ptr(), 16, 8, 16 * 4, &mut output);
+            assert!(size > 0);
+            let buffer = slice::from_raw_parts(output, size).to_vec();
+            webp::WebPFree(output as *mut _);
+            buffer
+        };
+
+        let frame = poster(&buffer, ColorMode::Rgba).unwrap();
+        assert_eq!(frame.dimensions(), (16, 8));
+        assert_eq!(frame.data(), &rgba[..]);
+
+        let frame = poster(&buffer, ColorMode::Rgb).unwrap();
+        let rgb: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|p| p[0..3].to_vec())
+            .collect();
+        assert_eq!(frame.data(), &rgb[..]);
+    }
+}
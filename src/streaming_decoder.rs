@@ -0,0 +1,555 @@
+use std::io::{ErrorKind, Read};
+
+use crate::{
+    compositor::Compositor,
+    demuxer::{DemuxState, Demuxer, CHUNK_HEADER_SIZE, RIFF_HEADER_SIZE},
+    DecoderOptions, Error, Frame,
+};
+
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Result of polling a [`StreamingDecoder`] for the next frame
+#[derive(Debug)]
+pub enum StreamingStatus {
+    /// Next frame of the animation
+    Frame(Frame),
+
+    /// Next frame has not been fully received yet. [`StreamingDecoder::push`] more data
+    NeedMoreData,
+
+    /// All frames have been decoded
+    Done,
+}
+
+/// A decoder for webp animation data that arrives incrementally
+///
+/// Bytes are fed with [`StreamingDecoder::push`] as they arrive, and each frame
+/// can be taken out with [`StreamingDecoder::next_frame`] as soon as its data is
+/// complete. Unlike [`Decoder`](crate::Decoder), truncated input is reported as
+/// [`StreamingStatus::NeedMoreData`] instead of an error
+///
+/// ```rust
+/// use webp_animation::prelude::*;
+///
+/// let buffer = std::fs::read("./data/animated.webp").unwrap();
+/// let mut decoder = StreamingDecoder::new();
+///
+/// let mut frames = Vec::new();
+/// for chunk in buffer.chunks(4096) {
+///   decoder.push(chunk);
+///
+///   while let StreamingStatus::Frame(frame) = decoder.next_frame().unwrap() {
+///     frames.push(frame);
+///   }
+/// }
+///
+/// assert_eq!(frames.len(), 10);
+/// ```
+///
+/// An [`std::io::Read`] source can be decoded with [`StreamingDecoder::from_reader`]
+///
+/// All data pushed is kept in memory until the decoder is dropped. The stream is parsed
+/// again only once a chunk of it has been received in full, so pushing data in small
+/// pieces stays cheap
+pub struct StreamingDecoder {
+    buffer: Vec<u8>,
+    options: DecoderOptions,
+    compositor: Option<Compositor>,
+    /// End of the last complete top-level chunk in `buffer`
+    chunk_end: usize,
+    /// Parsing progress at the time the stream last ran out of data
+    stalled_at: Option<usize>,
+}
+
+impl StreamingDecoder {
+    /// Construct a new streaming decoder with default options
+    pub fn new() -> Self {
+        StreamingDecoder::new_with_options(Default::default())
+    }
+
+    /// Construct a new streaming decoder with custom options
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let decoder = StreamingDecoder::new_with_options(DecoderOptions {
+    ///   use_threads: false,
+    ///   color_mode: ColorMode::Bgra,
+    ///   ..Default::default()
+    /// });
+    /// ```
+    pub fn new_with_options(options: DecoderOptions) -> Self {
+        Self {
+            buffer: Vec::new(),
+            options,
+            compositor: None,
+            chunk_end: 0,
+            stalled_at: None,
+        }
+    }
+
+    /// Wrap an [`std::io::Read`] source into an iterator of decoded frames
+    ///
+    /// Data is read from `reader` only as far as needed for producing the next frame.
+    /// If `reader` runs out of data in middle of the animation,
+    /// [`Error::UnexpectedEndOfStream`] is returned
+    ///
+    /// ```
+    /// # use webp_animation::prelude::*;
+    /// #
+    /// let file = std::fs::File::open("./data/animated.webp").unwrap();
+    ///
+    /// for frame in StreamingDecoder::from_reader(file, Default::default()) {
+    ///   assert_eq!(frame.unwrap().dimensions(), (400, 400));
+    /// }
+    /// ```
+    pub fn from_reader<R: Read>(reader: R, options: DecoderOptions) -> StreamingReader<R> {
+        StreamingReader {
+            reader,
+            decoder: StreamingDecoder::new_with_options(options),
+            finished: false,
+        }
+    }
+
+    /// Append more webp data to the stream
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns dimensions for webp frames (`width`, `height`), or `None` if the
+    /// header has not been received yet
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.compositor.as_ref().map(|c| c.dimensions())
+    }
+
+    /// Decode the next frame, if all of its data has been received
+    ///
+    /// Returns an [`Error`] in case the data received so far is malformed
+    pub fn next_frame(&mut self) -> Result<StreamingStatus, Error> {
+        // nothing new can be parsed before another chunk is complete
+        let progress = self.scan_chunks();
+        if self.stalled_at == Some(progress) {
+            return Ok(StreamingStatus::NeedMoreData);
+        }
+
+        let status = self.decode_next()?;
+        self.stalled_at = match status {
+            StreamingStatus::NeedMoreData => Some(progress),
+            _ => None,
+        };
+
+        Ok(status)
+    }
+
+    /// Walk the top-level chunks of the stream received so far, and return a position that
+    /// changes whenever a chunk, or the header of the next one, has been received in full.
+    /// Data that is not a RIFF container is reported as is
+    fn scan_chunks(&mut self) -> usize {
+        let buffer = &self.buffer;
+        if buffer.len() < RIFF_HEADER_SIZE || &buffer[0..4] != b"RIFF" || &buffer[8..12] != b"WEBP"
+        {
+            return buffer.len();
+        }
+
+        let mut end = self.chunk_end.max(RIFF_HEADER_SIZE);
+        let mut header_received = false;
+        while let Some(header) = buffer.get(end..end + CHUNK_HEADER_SIZE) {
+            let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            // chunks are padded to an even size
+            let next = (end + CHUNK_HEADER_SIZE)
+                .saturating_add(size)
+                .saturating_add(size & 1);
+            if next > buffer.len() {
+                header_received = true;
+                break;
+            }
+            end = next;
+        }
+
+        self.chunk_end = end;
+        end + header_received as usize
+    }
+
+    fn decode_next(&mut self) -> Result<StreamingStatus, Error> {
+        let demuxer = match Demuxer::new_partial(&self.buffer)? {
+            Some(demuxer) => demuxer,
+            None => return Ok(StreamingStatus::NeedMoreData),
+        };
+
+        if demuxer.state() == DemuxState::ParsingHeader {
+            return Ok(StreamingStatus::NeedMoreData);
+        }
+
+        let limits = &self.options.limits;
+        let compositor = match &mut self.compositor {
+            Some(compositor) => compositor,
+            None => {
+                let (width, height) = demuxer.dimensions();
+                limits.check_canvas((width, height))?;
+
+                log::trace!(
+                    "Streaming decoder parsed header, canvas {}x{}",
+                    width,
+                    height
+                );
+
+                let compositor = Compositor::new((width, height), &self.options)?;
+                limits.check_canvas(compositor.dimensions())?;
+                self.compositor.get_or_insert(compositor)
+            }
+        };
+
+        limits.check_frame_count(
+            demuxer.frame_count(),
+            compositor.dimensions(),
+            self.options.color_mode,
+        )?;
+
+        let index = compositor.next_index();
+        let fragment = match demuxer.fragment(index) {
+            Some(fragment) if fragment.complete => fragment,
+            _ if demuxer.state() == DemuxState::Done && index >= demuxer.frame_count() => {
+                return Ok(StreamingStatus::Done)
+            }
+            _ => return Ok(StreamingStatus::NeedMoreData),
+        };
+
+        limits.check_duration(compositor.timestamp() as u64 + fragment.header.duration as u64)?;
+
+        compositor.composite(&fragment)?;
+        let frame = compositor.frame();
+
+        log::trace!(
+            "Decoded a streamed frame {}, timestamp {}, {} bytes",
+            index,
+            frame.timestamp(),
+            frame.data().len()
+        );
+
+        Ok(StreamingStatus::Frame(frame))
+    }
+}
+
+impl Default for StreamingDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An iterator that produces decoded [`Frame`]'s from an [`std::io::Read`] source
+///
+/// Created with [`StreamingDecoder::from_reader`]
+pub struct StreamingReader<R> {
+    reader: R,
+    decoder: StreamingDecoder,
+    finished: bool,
+}
+
+impl<R: Read> StreamingReader<R> {
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+        loop {
+            match self.decoder.next_frame()? {
+                StreamingStatus::Frame(frame) => return Ok(Some(frame)),
+                StreamingStatus::Done => return Ok(None),
+                StreamingStatus::NeedMoreData => {}
+            }
+
+            let len = match self.reader.read(&mut chunk) {
+                Ok(0) => return Err(Error::UnexpectedEndOfStream),
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::ReadFailed(e.kind())),
+            };
+
+            self.decoder.push(&chunk[..len]);
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamingReader<R> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let result = self.read_frame();
+        if !matches!(result, Ok(Some(_))) {
+            self.finished = true;
+        }
+
+        result.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decoder::MAX_CANVAS_SIZE, BlendMode, ColorMode, CropRect, Decoder, DecoderLimits,
+        DecodingError, DisposeMode, EncodingConfig, EncodingType, FragmentRect, FrameBuilder,
+    };
+    use std::io::Cursor;
+
+    fn decode_in_chunks(buffer: &[u8], chunk_size: usize, color_mode: ColorMode) -> Vec<Frame> {
+        let mut decoder = StreamingDecoder::new_with_options(DecoderOptions {
+            color_mode,
+            ..Default::default()
+        });
+
+        let mut frames = Vec::new();
+        for chunk in buffer.chunks(chunk_size) {
+            decoder.push(chunk);
+
+            loop {
+                match decoder.next_frame().unwrap() {
+                    StreamingStatus::Frame(frame) => frames.push(frame),
+                    StreamingStatus::NeedMoreData => break,
+                    StreamingStatus::Done => return frames,
+                }
+            }
+        }
+
+        panic!("stream did not finish");
+    }
+
+    fn assert_matches_decoder(file: &str, chunk_size: usize, color_mode: ColorMode) {
+        let buffer = std::fs::read(file).unwrap();
+        let expected: Vec<_> = Decoder::new_with_options(
+            &buffer,
+            DecoderOptions {
+                color_mode,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .into_iter()
+        .collect();
+
+        let frames = decode_in_chunks(&buffer, chunk_size, color_mode);
+
+        assert_eq!(frames.len(), expected.len());
+        for (f1, f2) in frames.iter().zip(expected.iter()) {
+            assert_eq!(f1.timestamp(), f2.timestamp());
+            assert_eq!(f1.dimensions(), f2.dimensions());
+            assert_eq!(f1.color_mode(), f2.color_mode());
+            assert!(f1.data() == f2.data(), "frame {} differs", f1.timestamp());
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_decoder() {
+        assert_matches_decoder("./data/animated.webp", 1000, ColorMode::Rgba);
+        assert_matches_decoder("./data/animated.webp", 1, ColorMode::Bgra);
+        assert_matches_decoder("./data/example.webp", 777, ColorMode::Rgba);
+        assert_matches_decoder("./data/animated.webp", 500, ColorMode::RgbaPremultiplied);
+        assert_matches_decoder("./data/animated.webp", 500, ColorMode::ArgbPremultiplied);
+        assert_matches_decoder("./data/animated.webp", 500, ColorMode::Rgb565);
+    }
+
+    #[test]
+    fn test_streaming_small_pushes() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let mut decoder = StreamingDecoder::new();
+
+        // the first frame starts after the VP8X and ANIM chunks
+        decoder.push(&buffer[..12 + 18 + 14 + 8]);
+        assert!(matches!(
+            decoder.next_frame().unwrap(),
+            StreamingStatus::NeedMoreData
+        ));
+        let stalled_at = decoder.stalled_at;
+        assert!(stalled_at.is_some());
+
+        // bytes within the frame do not cause the stream to be parsed again
+        for byte in &buffer[52..100] {
+            decoder.push(&[*byte]);
+            assert!(matches!(
+                decoder.next_frame().unwrap(),
+                StreamingStatus::NeedMoreData
+            ));
+            assert_eq!(decoder.stalled_at, stalled_at);
+        }
+
+        let frames = decode_in_chunks(&buffer, 3, ColorMode::Rgba);
+        assert_eq!(frames.len(), 10);
+    }
+
+    #[test]
+    fn test_streaming_scaled() {
+        let buffer = std::fs::read("./data/example.webp").unwrap();
+        let options = || DecoderOptions {
+            crop: Some(CropRect {
+                left: 5,
+                top: 7,
+                width: 90,
+                height: 80,
+            }),
+            output_size: Some((30, 20)),
+            ..Default::default()
+        };
+
+        let expected: Vec<_> = Decoder::new_with_options(&buffer, options())
+            .unwrap()
+            .into_iter()
+            .collect();
+        let frames: Vec<_> = StreamingDecoder::from_reader(Cursor::new(&buffer), options())
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(frames.len(), expected.len());
+        for (f1, f2) in frames.iter().zip(expected.iter()) {
+            assert_eq!(f1.timestamp(), f2.timestamp());
+            assert_eq!(f1.dimensions(), (30, 20));
+            assert!(f1.data() == f2.data(), "frame {} differs", f1.timestamp());
+        }
+    }
+
+    #[test]
+    fn test_streaming_need_more_data() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+
+        let mut decoder = StreamingDecoder::new();
+        assert!(matches!(
+            decoder.next_frame().unwrap(),
+            StreamingStatus::NeedMoreData
+        ));
+        assert_eq!(decoder.dimensions(), None);
+
+        decoder.push(&buffer[..600]);
+        assert!(matches!(
+            decoder.next_frame().unwrap(),
+            StreamingStatus::NeedMoreData
+        ));
+        assert_eq!(decoder.dimensions(), Some((400, 400)));
+
+        decoder.push(&buffer[600..]);
+        assert!(matches!(
+            decoder.next_frame().unwrap(),
+            StreamingStatus::Frame(_)
+        ));
+    }
+
+    #[test]
+    fn test_streaming_failure() {
+        let mut decoder = StreamingDecoder::new();
+        decoder.push(b"RIFF\x24\x00\x00\x00WEBPJUNK\x18\x00\x00\x00");
+        decoder.push(&[0u8; 24]);
+        assert_eq!(
+            decoder.next_frame().unwrap_err(),
+            Error::DecodeFailed(DecodingError::BitstreamError)
+        );
+
+        let mut decoder = StreamingDecoder::new();
+        decoder.push(&[0x55; 64]);
+        assert_eq!(
+            decoder.next_frame().unwrap_err(),
+            Error::DecodeFailed(DecodingError::BitstreamError)
+        );
+
+        let mut decoder = StreamingDecoder::new();
+        decoder.push(&[
+            0x2f, 0xff, 0xff, 0xff, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(
+            decoder.next_frame().unwrap_err(),
+            Error::TooLargeCanvas(16384, 12288, MAX_CANVAS_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_streaming_limits() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+        let decode = |limits| {
+            let options = DecoderOptions {
+                limits,
+                ..Default::default()
+            };
+            StreamingDecoder::from_reader(Cursor::new(&buffer), options)
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(
+            decode(DecoderLimits {
+                max_frame_count: Some(9),
+                ..Default::default()
+            })
+            .unwrap_err(),
+            Error::TooManyFrames(10, 9)
+        );
+        assert_eq!(
+            decode(DecoderLimits {
+                max_duration_ms: Some(399),
+                ..Default::default()
+            })
+            .unwrap_err(),
+            Error::TooLongDuration(400, 399)
+        );
+        assert_eq!(
+            decode(DecoderLimits {
+                max_decoded_bytes: Some(6_399_999),
+                ..Default::default()
+            })
+            .unwrap_err(),
+            Error::TooManyDecodedBytes(6_400_000, 6_399_999)
+        );
+    }
+
+    #[test]
+    fn test_streaming_reader() {
+        let buffer = std::fs::read("./data/animated.webp").unwrap();
+
+        let frames: Result<Vec<_>, _> =
+            StreamingDecoder::from_reader(Cursor::new(&buffer), Default::default()).collect();
+        assert_eq!(frames.unwrap().len(), 10);
+
+        let mut reader =
+            StreamingDecoder::from_reader(Cursor::new(&buffer[..2000]), Default::default());
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(
+            reader.find_map(|f| f.err()).unwrap(),
+            Error::UnexpectedEndOfStream
+        );
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_streaming_timestamp_overflow() {
+        // 130 frames of the maximum duration add up past i32::MAX
+        let mut builder = FrameBuilder::new((2, 2)).unwrap();
+        let config = EncodingConfig {
+            encoding_type: EncodingType::new_lossless(),
+            ..Default::default()
+        };
+        for _ in 0..130 {
+            let rect = FragmentRect {
+                x_offset: 0,
+                y_offset: 0,
+                width: 2,
+                height: 2,
+            };
+            builder
+                .add_fragment(
+                    &[0u8; 2 * 2 * 4],
+                    rect,
+                    0xff_ffff,
+                    BlendMode::NoBlend,
+                    DisposeMode::None,
+                    &config,
+                )
+                .unwrap();
+        }
+        let webp_data = builder.finalize().unwrap();
+
+        let frames = decode_in_chunks(&webp_data, 1000, ColorMode::Rgba);
+        assert_eq!(frames.len(), 130);
+        assert_eq!(frames[127].timestamp(), 128 * 0xff_ffff);
+        assert_eq!(frames[128].timestamp(), i32::MAX);
+        assert_eq!(frames[129].timestamp(), i32::MAX);
+    }
+}
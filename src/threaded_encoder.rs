@@ -0,0 +1,226 @@
+use std::borrow::Cow;
+
+use crate::{
+    frame_builder::encode_fragment, BlendMode, ColorMode, DisposeMode, EncoderOptions,
+    EncodingConfig, Error, FragmentRect, FrameBuilder, FrameView, WebPData,
+};
+
+/// Longest duration of a single frame, the format stores 24 bits
+const MAX_DURATION: i64 = 0xff_ffff;
+
+/// Frame encoding of [`Encoder`](crate::Encoder) on worker threads, see
+/// [`EncoderOptions::threads`]
+///
+/// Changed areas are found on the calling thread, and encoded as independent sub-frames by
+/// a [`FrameBuilder`]. A sub-frame is only queued for encoding once the next frame gives its
+/// duration
+pub(crate) struct ThreadedEncoder {
+    builder: FrameBuilder,
+    dimensions: (u32, u32),
+    color_mode: ColorMode,
+    kmax: isize,
+    /// Previous frame in input color mode, without row padding
+    previous: Vec<u8>,
+    pending: Option<PendingFrame>,
+    frames_since_keyframe: isize,
+    /// Encoded transparent pixel, for extending frames longer than [`MAX_DURATION`]
+    empty_fragment: Option<Vec<u8>>,
+}
+
+struct PendingFrame {
+    data: Vec<u8>,
+    rect: FragmentRect,
+    timestamp: i32,
+    config: EncodingConfig,
+}
+
+impl ThreadedEncoder {
+    pub fn new(dimensions: (u32, u32), options: &EncoderOptions) -> Result<Self, Error> {
+        // kmin does not matter, as key-frames are only inserted when kmax requires
+        let (_, kmax) = options.keyframe_distances();
+
+        Ok(Self {
+            builder: FrameBuilder::new_with_options(dimensions, options.clone())?,
+            dimensions,
+            color_mode: options.color_mode,
+            kmax,
+            previous: Vec::new(),
+            pending: None,
+            frames_since_keyframe: 0,
+            empty_fragment: None,
+        })
+    }
+
+    /// Add a frame displayed from `timestamp` on. A frame identical to the previous one
+    /// extends its duration
+    pub fn add(
+        &mut self,
+        view: &FrameView,
+        timestamp: i32,
+        config: &EncodingConfig,
+    ) -> Result<(), Error> {
+        if view.dimensions != self.dimensions {
+            return Err(Error::WrongFrameDimensions(
+                view.dimensions,
+                self.dimensions,
+            ));
+        }
+
+        let pixel_size = self.color_mode.size();
+        let row_size = self.dimensions.0 as usize * pixel_size;
+        let mut current = Vec::with_capacity(row_size * self.dimensions.1 as usize);
+        for row in view.pixels(self.color_mode)?.chunks(view.stride) {
+            current.extend_from_slice(&row[..row_size]);
+        }
+
+        let full_rect = FragmentRect {
+            x_offset: 0,
+            y_offset: 0,
+            width: self.dimensions.0,
+            height: self.dimensions.1,
+        };
+        let rect = match self.previous.is_empty() {
+            true => full_rect,
+            false => match changed_rect(&self.previous, &current, self.dimensions, pixel_size) {
+                Some(_) if self.kmax == 1 => full_rect,
+                Some(_) if self.kmax > 1 && self.frames_since_keyframe + 1 >= self.kmax => {
+                    full_rect
+                }
+                Some(rect) => rect,
+                None => return Ok(()),
+            },
+        };
+
+        self.flush(timestamp)?;
+
+        self.frames_since_keyframe = match rect == full_rect {
+            true => 0,
+            false => self.frames_since_keyframe + 1,
+        };
+        self.pending = Some(PendingFrame {
+            data: crop(&current, self.dimensions.0, pixel_size, rect),
+            rect,
+            timestamp,
+            config: config.clone(),
+        });
+        self.previous = current;
+
+        Ok(())
+    }
+
+    /// Add the last frame, displayed until `timestamp`, and assemble the animation
+    pub fn finish(mut self, timestamp: i32) -> Result<WebPData, Error> {
+        self.flush(timestamp)?;
+        self.builder.finalize()
+    }
+
+    /// Queue the pending frame for encoding, displayed until `timestamp`
+    fn flush(&mut self, timestamp: i32) -> Result<(), Error> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let mut duration = timestamp as i64 - pending.timestamp as i64;
+        self.builder.add_fragment_data(
+            Cow::Owned(pending.data),
+            pending.rect,
+            duration.min(MAX_DURATION) as i32,
+            BlendMode::NoBlend,
+            DisposeMode::None,
+            &pending.config,
+        )?;
+
+        // like libwebp, continue a too long frame with transparent pixels blended on top
+        while duration > MAX_DURATION {
+            duration -= MAX_DURATION;
+            let bitstream = match &self.empty_fragment {
+                Some(bitstream) => bitstream.clone(),
+                None => {
+                    let bitstream = encode_fragment(
+                        0,
+                        &[0; 4],
+                        (1, 1),
+                        ColorMode::Rgba,
+                        &EncodingConfig::default(),
+                    )?;
+                    self.empty_fragment.get_or_insert(bitstream).clone()
+                }
+            };
+            let rect = FragmentRect {
+                x_offset: 0,
+                y_offset: 0,
+                width: 1,
+                height: 1,
+            };
+            self.builder.add_encoded(
+                bitstream,
+                rect,
+                duration.min(MAX_DURATION) as i32,
+                BlendMode::Blend,
+                DisposeMode::None,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounding rectangle of the pixels that differ between `previous` and `current`, with the
+/// offsets rounded down to even numbers. `None` if the frames are identical
+fn changed_rect(
+    previous: &[u8],
+    current: &[u8],
+    (width, height): (u32, u32),
+    pixel_size: usize,
+) -> Option<FragmentRect> {
+    let row_size = width as usize * pixel_size;
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+
+    let rows = previous
+        .chunks_exact(row_size)
+        .zip(current.chunks_exact(row_size));
+    for (y, (previous, current)) in rows.enumerate().take(height as usize) {
+        if previous == current {
+            continue;
+        }
+
+        let pixels = || {
+            previous
+                .chunks_exact(pixel_size)
+                .zip(current.chunks_exact(pixel_size))
+        };
+        let left = pixels().position(|(a, b)| a != b).unwrap_or(0);
+        let right = width as usize - 1 - pixels().rev().position(|(a, b)| a != b).unwrap_or(0);
+
+        bounds = Some(match bounds {
+            Some((l, top, r, _)) => (l.min(left), top, r.max(right), y),
+            None => (left, y, right, y),
+        });
+    }
+
+    bounds.map(|(left, top, right, bottom)| {
+        // the format stores the offsets halved
+        let (x_offset, y_offset) = (left & !1, top & !1);
+        FragmentRect {
+            x_offset: x_offset as u32,
+            y_offset: y_offset as u32,
+            width: (right + 1 - x_offset) as u32,
+            height: (bottom + 1 - y_offset) as u32,
+        }
+    })
+}
+
+/// Pixels of `rect` out of a frame `width` pixels wide
+fn crop(data: &[u8], width: u32, pixel_size: usize, rect: FragmentRect) -> Vec<u8> {
+    let row_size = width as usize * pixel_size;
+    let start = rect.x_offset as usize * pixel_size;
+    let end = start + rect.width as usize * pixel_size;
+
+    data.chunks_exact(row_size)
+        .skip(rect.y_offset as usize)
+        .take(rect.height as usize)
+        .flat_map(|row| &row[start..end])
+        .copied()
+        .collect()
+}
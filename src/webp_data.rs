@@ -24,6 +24,10 @@ impl WebPData {
     }
 
     fn as_slice(&self) -> &[u8] {
+        if self.data.bytes.is_null() {
+            return &[];
+        }
+
         unsafe { slice::from_raw_parts(self.data.bytes, self.data.size) }
     }
 }